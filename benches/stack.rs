@@ -0,0 +1,60 @@
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use kara::{Interpreter, OutputBuffer};
+
+//Compares the two stack layouts. Only whichever one the vm was built with
+//gets run, so run it with and without --features nan_boxing to compare
+const SCRIPT: &str = "
+fun fib(n) {
+  if (n < 2) return n;
+  return fib(n - 1) + fib(n - 2);
+}
+
+var total = 0;
+for (var i = 0; i < 1000; i = i + 1) {
+  total = total + i * 0.5;
+}
+print fib(20) + total;
+";
+
+//roughly what a loop body does to the stack, locals get copied up,
+//combined and written back. Some of the ints are too big for a NanBox
+//so its boxing and collecting is part of what's measured
+const CHURN: &str = "
+var small = 1;
+var half = 0.5;
+var big = 1152921504606846976;
+var flag = true;
+for (var i = 0; i < 4096; i = i + 1) {
+  small = small + 1;
+  half = half + 1;
+  big = big + 1;
+  flag = !flag;
+}
+";
+
+fn bench_script(c: &mut Criterion, name: &str, source: &'static str) {
+    let layout = if cfg!(feature = "nan_boxing") { "nan_boxed" } else { "enum" };
+
+    c.bench_function(&format!("{name}/{layout}"), |b| {
+        b.iter_batched(
+            || {
+                let mut interpreter = Interpreter::new();
+                interpreter.set_output(OutputBuffer::new());
+                interpreter
+            },
+            |mut interpreter| interpreter.eval(source).unwrap(),
+            BatchSize::SmallInput,
+        )
+    });
+}
+
+fn stack(c: &mut Criterion) {
+    bench_script(c, "stack", CHURN);
+}
+
+fn script(c: &mut Criterion) {
+    bench_script(c, "script", SCRIPT);
+}
+
+criterion_group!(benches, stack, script);
+criterion_main!(benches);
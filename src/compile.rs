@@ -1,12 +1,24 @@
-use crate::Op::*;
+use crate::vm::Op;
+use crate::vm::Op::*;
 use crate::Token;
+use crate::lex::{unescape, Scanner};
 use crate::TokenType;
-use crate::Value;
-use std::vec::IntoIter;
+use crate::vm::Value;
+use crate::heap::{Gc, Trace, Tracer};
+use crate::object::{Function, ObjString};
+use crate::vm::{Chunk, Vm};
+use crate::diagnostic::{Diagnostic, Note, Span};
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::Range;
 use strum_macros::FromRepr;
+use CompileErrorKind::*;
 use Precedence::*;
 use TokenType::*;
 
+//OpWide plus the op's own byte makes a 24 bit index
+const CONSTANTS_MAX: usize = 1 << 24;
+
 //only needs lifetime paameter because
 //token contains string slice
 struct Parser<'a> {
@@ -14,14 +26,14 @@ struct Parser<'a> {
     current: Token<'a>,
 }
 
+//Rules take can_assign so that only a target parsed at
+//assignment precedence may consume a trailing '='
 struct ParseRule {
-    prefix: fn(&mut Compiler),
-    infix: fn(&mut Compiler),
+    prefix: Option<fn(&mut Compiler, bool)>,
+    infix: Option<fn(&mut Compiler, bool)>,
     prec: Precedence,
 }
 
-//const null_fn = |s: &mut Compiler| {};
-
 //impl ParseRule {
 //    fn new(pre: fn(&mut self), in: fn(&mut self), prec: Precedence) -> Self {
 //        ParseRule{ prefix: pre, infix: in, prec: prec }
@@ -44,56 +56,370 @@ enum Precedence {
     Primary,
 }
 
+//depth is None between declaring a local and finishing
+//its initializer, so `var a = a;` can be caught
+struct Local<'a> {
+    name: Token<'a>,
+    depth: Option<usize>,
+    //captured locals get closed over instead of popped
+    is_captured: bool,
+}
+
+//is_local means index refers to a local slot in the enclosing
+//function, otherwise to one of the enclosing function's upvalues
+#[derive(PartialEq)]
+struct UpvalueRef {
+    index: u8,
+    is_local: bool,
+}
+
+#[derive(PartialEq)]
+enum FunctionKind {
+    Script,
+    Function,
+    Method,
+    Initializer,
+}
+
+//everything that's specific to the function currently being
+//compiled, a new one gets pushed for every nested function
+struct FunctionState<'a> {
+    function: Function,
+    kind: FunctionKind,
+    //index into this vec is the local's slot in the call frame
+    locals: Vec<Local<'a>>,
+    upvalues: Vec<UpvalueRef>,
+    scope_depth: usize,
+    //where each constant already in the pool is, so they only go in once
+    constants: HashMap<ConstantKey, usize>,
+}
+
+//floats by their bits so 0.0 and -0.0 stay apart, ints separately so
+//1 and 1.0 stay apart too. strings are interned so the object's
+//address is as good as its contents
+#[derive(PartialEq, Eq, Hash)]
+enum ConstantKey {
+    Number(u64),
+    Int(i64),
+    Str(*const ObjString),
+}
+
+impl<'a> FunctionState<'a> {
+    fn new(kind: FunctionKind, name: Option<Gc<ObjString>>) -> Self {
+        //methods keep the receiver in slot zero so it can be
+        //resolved like any other local
+        let slot_zero = match kind {
+            FunctionKind::Method | FunctionKind::Initializer => "this",
+            _ => "",
+        };
+
+        Self {
+            function: Function {
+                arity: 0,
+                upvalue_count: 0,
+                chunk: Chunk::new(),
+                name,
+            },
+            kind,
+            //slot zero holds the function being called
+            locals: vec![Local {
+                name: Token {
+                    kind: Blank,
+                    line_num: 0,
+                    column: 0,
+                    span: Span::default(),
+                    content: slot_zero,
+                },
+                depth: Some(0),
+                is_captured: false,
+            }],
+            upvalues: Vec::new(),
+            scope_depth: 0,
+            constants: HashMap::new(),
+        }
+    }
+
+    //also returns whether the local has finished initializing
+    fn resolve_local(&self, name: Token) -> Option<(u8, bool)> {
+        let (slot, local) = self
+            .locals
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(_, local)| local.name.content == name.content)?;
+
+        Some((slot as u8, local.depth.is_some()))
+    }
+
+    //reuses an existing upvalue if the function already captures the
+    //variable, None if there's no room for another one
+    fn add_upvalue(&mut self, index: u8, is_local: bool) -> Option<u8> {
+        let upvalue = UpvalueRef { index, is_local };
+        if let Some(idx) = self.upvalues.iter().position(|u| *u == upvalue) {
+            return Some(idx as u8);
+        }
+
+        if self.upvalues.len() == 256 {
+            return None;
+        }
+
+        self.upvalues.push(upvalue);
+        self.function.upvalue_count = self.upvalues.len();
+        Some((self.upvalues.len() - 1) as u8)
+    }
+}
+
+//one per class declaration currently being compiled
+struct ClassState {
+    has_superclass: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CompileErrorKind {
+    //includes a missing expression
+    UnexpectedToken,
+    InvalidAssignmentTarget,
+    Redeclaration,
+    ReadInOwnInitializer,
+    InvalidReturn,
+    InvalidThis,
+    InvalidSuper,
+    InheritFromSelf,
+    //one of the limits on constants, locals, jumps etc.
+    LimitExceeded,
+    InvalidEscape,
+    //the rest come from the lexer
+    UnterminatedString,
+    UnexpectedCharacter,
+    MalformedNumber,
+}
+
+//lexeme is None when the error is at the end of the source,
+//or came from the lexer and so isn't at any token
+#[derive(Debug, Clone)]
+pub struct CompileError {
+    pub kind: CompileErrorKind,
+    pub message: String,
+    pub line: usize,
+    pub column: usize,
+    pub span: Span,
+    pub lexeme: Option<String>,
+    pub notes: Vec<Note>,
+}
+
+impl From<&CompileError> for Diagnostic {
+    fn from(err: &CompileError) -> Self {
+        Diagnostic {
+            message: err.message.clone(),
+            span: err.span,
+            notes: err.notes.clone(),
+        }
+    }
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.lexeme, self.kind) {
+            (Some(lexeme), _) => write!(f, "[line {}] Error at '{}': {}", self.line, lexeme, self.message),
+            (None, UnterminatedString | UnexpectedCharacter | MalformedNumber) => {
+                write!(f, "[line {}] Error: {}", self.line, self.message)
+            }
+            (None, _) => write!(f, "[line {}] Error at end: {}", self.line, self.message),
+        }
+    }
+}
+
 pub struct Compiler<'a> {
     parser: Parser<'a>,
-    tokens: IntoIter<Token<'a>>,
-    //leave as public for now, but possibly change later
-    pub bytecode: Vec<u8>,
-    pub const_pool: Vec<Value>,
+    //tokens are scanned as the parser asks for them
+    tokens: Scanner<'a>,
+    //innermost function is last
+    states: Vec<FunctionState<'a>>,
+    classes: Vec<ClassState>,
+    //constants get allocated on the vm's heap
+    vm: &'a mut Vm,
+    errors: Vec<CompileError>,
+    //set after an error until the next statement boundary,
+    //so one mistake doesn't cause a cascade of others
+    panic_mode: bool,
+    //print the value of top level expression statements instead of dropping it
+    echo: bool,
 }
 
 impl<'a> Compiler<'a> {
-    pub fn new(tokens: Vec<Token<'a>>) -> Self {
+    pub(crate) fn new(source: &'a str, vm: &'a mut Vm) -> Self {
         Self {
-            tokens: tokens.into_iter(),
+            vm,
+            tokens: Scanner::new(source),
             parser: Parser {
                 previous: Token {
                     kind: Blank,
                     line_num: 0,
+                    column: 0,
+                    span: Span::default(),
                     content: "",
                 },
 
                 current: Token {
                     kind: Blank,
                     line_num: 0,
+                    column: 0,
+                    span: Span::default(),
                     content: "",
                 },
             },
-            const_pool: vec![],
-            bytecode: vec![],
+            states: vec![FunctionState::new(FunctionKind::Script, None)],
+            classes: Vec::new(),
+            errors: Vec::new(),
+            panic_mode: false,
+            echo: false,
         }
     }
 
+    //for the repl, so typing `1 + 2;` shows 3
+    pub(crate) fn echo_expressions(mut self) -> Self {
+        self.echo = true;
+        self
+    }
+
     //just implement the authors way, and change later
-    pub fn compile(&mut self) {
+    pub(crate) fn compile(mut self) -> Result<Gc<Function>, Vec<CompileError>> {
         self.advance();
         //self.expression();
         
         while !self.check_match(Eof) { 
             self.declaration();
         }
-        if self.parser.previous.kind != Eof { panic!("Expected 'EOF'"); } 
+
+        let function = self.end_function().0;
+        if !self.errors.is_empty() {
+            return Err(self.errors);
+        }
+        Ok(self.alloc(function))
+    }
+
+    fn error(&mut self, kind: CompileErrorKind, msg: &str) {
+        self.error_at(self.parser.previous, kind, msg);
+    }
+
+    fn error_at_current(&mut self, kind: CompileErrorKind, msg: &str) {
+        self.error_at(self.parser.current, kind, msg);
+    }
+
+    fn error_at(&mut self, token: Token, kind: CompileErrorKind, msg: &str) {
+        self.error_with_notes(token, kind, msg, Vec::new());
+    }
+
+    fn error_with_notes(&mut self, token: Token, kind: CompileErrorKind, msg: &str, notes: Vec<Note>) {
+        if self.panic_mode {
+            return;
+        }
+        self.panic_mode = true;
+
+        self.errors.push(CompileError {
+            kind,
+            message: msg.to_owned(),
+            line: token.line_num,
+            column: token.column,
+            span: token.span,
+            notes,
+            lexeme: (token.kind != Eof).then(|| token.content.to_owned()),
+        });
+    }
+
+    //skip tokens until something that looks like the start of a statement
+    fn synchronize(&mut self) {
+        self.panic_mode = false;
+
+        while self.parser.current.kind != Eof {
+            if self.parser.previous.kind == Semicolon {
+                return;
+            }
+
+            match self.parser.current.kind {
+                Class | Fun | Var | For | If | While | Print | Return => return,
+                _ => self.advance(),
+            }
+        }
+    }
+
+    //functions that are still being compiled aren't on the heap
+    //yet, so the vm can't see their constants without help
+    fn mark_roots(states: &[FunctionState], tracer: &mut Tracer) {
+        for state in states {
+            state.function.trace(tracer);
+        }
+    }
+
+    fn alloc<T: Trace>(&mut self, value: T) -> Gc<T> {
+        let states = &self.states;
+        self.vm
+            .alloc_rooted(value, |tracer| Self::mark_roots(states, tracer))
+    }
+
+    fn intern(&mut self, chars: &str) -> Gc<ObjString> {
+        let states = &self.states;
+        self.vm
+            .intern_rooted(chars, |tracer| Self::mark_roots(states, tracer))
+    }
+
+    fn state(&mut self) -> &mut FunctionState<'a> {
+        self.states.last_mut().unwrap()
+    }
+
+    fn chunk(&mut self) -> &mut Chunk {
+        &mut self.state().function.chunk
+    }
+
+    fn emit(&mut self, op: Op) {
+        self.emit_byte(op as u8);
+    }
+
+    fn emit_byte(&mut self, byte: u8) {
+        let line = self.parser.previous.line_num;
+        self.chunk().write(byte, line);
+    }
+
+    //falling off the end of a function returns nil,
+    //initializers always return the instance
+    fn emit_return(&mut self) {
+        if self.state().kind == FunctionKind::Initializer {
+            self.emit(OpGetLocal);
+            self.emit_byte(0);
+        } else {
+            self.emit(OpNil);
+        }
+        self.emit(OpReturn);
+    }
+
+    fn end_function(&mut self) -> (Function, Vec<UpvalueRef>) {
+        self.emit_return();
+        let state = self.states.pop().unwrap();
+        (state.function, state.upvalues)
     }
 
     fn advance(&mut self) {
         self.parser.previous = self.parser.current;
-        self.parser.current = match self.tokens.next() {
-            Some(token) => token,
-            None => Token { 
-                kind: Blank, 
-                line_num: self.parser.previous.line_num,
-                content: "" 
-            },
+        loop {
+            self.parser.current = match self.tokens.next() {
+                Some(token) => token,
+                //keep handing out Eof so error recovery can't run off the end
+                None => Token { 
+                    kind: Eof, 
+                    line_num: self.parser.previous.line_num,
+                    column: self.parser.previous.column,
+                    span: self.parser.previous.span,
+                    content: "" 
+                },
+            };
+            if self.parser.current.kind != Error {
+                return;
+            }
+
+            //every lexer error gets reported, but the parse errors
+            //that follow from skipping the bad token don't
+            self.errors.append(&mut self.tokens.take_errors());
+            self.panic_mode = true;
         }
     }
     
@@ -103,21 +429,536 @@ impl<'a> Compiler<'a> {
         true
     }
     
+    //for closing brackets, so the error can point back at the opening one
+    fn consume_closing(&mut self, kind: TokenType, msg: &str, opener: Token) {
+        if self.parser.current.kind != kind {
+            let note = Note {
+                message: format!("to match this '{}'", opener.content),
+                span: Some(opener.span),
+            };
+            self.error_with_notes(self.parser.current, UnexpectedToken, msg, vec![note]);
+            return;
+        }
+        self.advance();
+    }
+
+    fn consume(&mut self, kind: TokenType, msg: &str) {
+        if self.parser.current.kind != kind {
+            self.error_at_current(UnexpectedToken, msg);
+            return;
+        }
+        self.advance();
+    }
+
     fn declaration(&mut self) {
-        self.statement();
+        if self.check_match(Class) {
+            self.class_declaration();
+        } else if self.check_match(Fun) {
+            self.fun_declaration();
+        } else if self.check_match(Var) {
+            self.var_declaration();
+        } else {
+            self.statement();
+        }
+
+        if self.panic_mode {
+            self.synchronize();
+        }
+    }
+
+    fn class_declaration(&mut self) {
+        self.consume(Identifier, "Expected class name");
+        let class_name = self.parser.previous;
+        let name_constant = self.identifier_constant(class_name);
+        self.declare_variable();
+
+        self.emit_constant_op(OpClass, name_constant);
+        self.define_variable(name_constant);
+
+        self.classes.push(ClassState {
+            has_superclass: false,
+        });
+
+        if self.check_match(Less) {
+            self.consume(Identifier, "Expected superclass name");
+            self.variable(false);
+
+            if class_name.content == self.parser.previous.content {
+                self.error(InheritFromSelf, "A class can't inherit from itself");
+            }
+
+            //the superclass lives in a local named super for the rest of
+            //the class body, so each class declaration gets its own scope
+            self.begin_scope();
+            self.add_local(Self::synthetic_token("super"));
+            self.define_variable(0);
+
+            self.named_variable(class_name, false);
+            self.emit(OpInherit);
+            self.classes.last_mut().unwrap().has_superclass = true;
+        }
+
+        //put the class back on the stack so methods can be bound to it
+        self.named_variable(class_name, false);
+        self.consume(LeftBrace, "Expected '{' before class body");
+        let opener = self.parser.previous;
+        while self.parser.current.kind != RightBrace && self.parser.current.kind != Eof {
+            self.method();
+        }
+        self.consume_closing(RightBrace, "Expected '}' after class body", opener);
+        self.emit(OpPop);
+
+        if self.classes.pop().unwrap().has_superclass {
+            self.end_scope();
+        }
+    }
+
+    fn synthetic_token(content: &'static str) -> Token<'static> {
+        Token {
+            kind: Identifier,
+            line_num: 0,
+            column: 0,
+            span: Span::default(),
+            content,
+        }
+    }
+
+    fn method(&mut self) {
+        self.consume(Identifier, "Expected method name");
+        let constant = self.identifier_constant(self.parser.previous);
+
+        let kind = if self.parser.previous.content == "init" {
+            FunctionKind::Initializer
+        } else {
+            FunctionKind::Method
+        };
+        self.function(kind);
+
+        self.emit_constant_op(OpMethod, constant);
+    }
+
+    fn fun_declaration(&mut self) {
+        let global = self.parse_variable("Expected function name");
+        //initialized straight away so the body can call itself
+        self.mark_initialized();
+        self.function(FunctionKind::Function);
+        self.define_variable(global);
+    }
+
+    fn function(&mut self, kind: FunctionKind) {
+        let name = self.intern(self.parser.previous.content);
+        self.states.push(FunctionState::new(kind, Some(name)));
+        //never closed, end_function throws the whole state away
+        self.begin_scope();
+
+        self.consume(LeftParen, "Expected '(' after function name");
+        let opener = self.parser.previous;
+        if self.parser.current.kind != RightParen {
+            loop {
+                if self.state().function.arity == 255 {
+                    self.error_at_current(LimitExceeded, "Can't have more than 255 parameters");
+                } else {
+                    self.state().function.arity += 1;
+                }
+
+                let param = self.parse_variable("Expected parameter name");
+                self.define_variable(param);
+
+                if !self.check_match(Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume_closing(RightParen, "Expected ')' after parameters", opener);
+        self.consume(LeftBrace, "Expected '{' before function body");
+        self.block();
+
+        let (function, upvalues) = self.end_function();
+        let function = self.alloc(function);
+        let idx = self.make_constant(Value::Function(function));
+        self.emit_constant_op(OpClosure, idx);
+
+        //tells the vm where to capture each upvalue from
+        for upvalue in upvalues {
+            self.emit_byte(upvalue.is_local as u8);
+            self.emit_byte(upvalue.index);
+        }
+    }
+
+    fn var_declaration(&mut self) {
+        let global = self.parse_variable("Expected variable name");
+
+        if self.check_match(Equal) {
+            self.expression();
+        } else {
+            self.emit(OpNil);
+        }
+        self.consume(Semicolon, "Expected ';' after variable declaration");
+
+        self.define_variable(global);
+    }
+
+    //returns the name's constant index for globals, locals don't need one
+    fn parse_variable(&mut self, msg: &str) -> usize {
+        self.consume(Identifier, msg);
+
+        self.declare_variable();
+        if self.state().scope_depth > 0 {
+            return 0;
+        }
+
+        self.identifier_constant(self.parser.previous)
+    }
+
+    fn declare_variable(&mut self) {
+        let state = self.state();
+        if state.scope_depth == 0 {
+            return;
+        }
+
+        let name = self.parser.previous;
+        let state = self.state();
+        //only look at locals in the current scope
+        for local in state.locals.iter().rev() {
+            if local.depth.is_some_and(|depth| depth < state.scope_depth) {
+                break;
+            }
+
+            if local.name.content == name.content {
+                let note = Note {
+                    message: "previously declared here".to_owned(),
+                    span: Some(local.name.span),
+                };
+                self.error_with_notes(
+                    name,
+                    Redeclaration,
+                    "Already a variable with this name in this scope",
+                    vec![note],
+                );
+                break;
+            }
+        }
+
+        self.add_local(name);
+    }
+
+    fn add_local(&mut self, name: Token<'a>) {
+        if self.state().locals.len() == 256 {
+            self.error(LimitExceeded, "Too many local variables in function");
+            return;
+        }
+
+        self.state().locals.push(Local {
+            name,
+            depth: None,
+            is_captured: false,
+        });
+    }
+
+    fn define_variable(&mut self, global: usize) {
+        //a local's value is already sitting in its slot
+        if self.state().scope_depth > 0 {
+            self.mark_initialized();
+            return;
+        }
+
+        self.emit_constant_op(OpDefineGlobal, global);
+    }
+
+    fn mark_initialized(&mut self) {
+        let state = self.state();
+        //a global function has nothing to mark
+        if state.scope_depth == 0 {
+            return;
+        }
+        state.locals.last_mut().unwrap().depth = Some(state.scope_depth);
+    }
+
+    fn resolve_local(&mut self, state_idx: usize, name: Token) -> Option<u8> {
+        let (slot, initialized) = self.states[state_idx].resolve_local(name)?;
+        if !initialized {
+            self.error(ReadInOwnInitializer, "Can't read local variable in its own initializer");
+        }
+        Some(slot)
+    }
+
+    //walks outwards through the enclosing functions, threading the
+    //variable through an upvalue in each function along the way
+    fn resolve_upvalue(&mut self, state_idx: usize, name: Token) -> Option<u8> {
+        if state_idx == 0 {
+            return None;
+        }
+
+        let enclosing = state_idx - 1;
+        if let Some(local) = self.resolve_local(enclosing, name) {
+            self.states[enclosing].locals[local as usize].is_captured = true;
+            return Some(self.add_upvalue(state_idx, local, true));
+        }
+
+        let upvalue = self.resolve_upvalue(enclosing, name)?;
+        Some(self.add_upvalue(state_idx, upvalue, false))
+    }
+
+    fn add_upvalue(&mut self, state_idx: usize, index: u8, is_local: bool) -> u8 {
+        self.states[state_idx]
+            .add_upvalue(index, is_local)
+            .unwrap_or_else(|| {
+                self.error(LimitExceeded, "Too many closure variables in function");
+                0
+            })
+    }
+
+    //globals are looked up by name at runtime, so
+    //the name itself goes in the constant pool
+    fn identifier_constant(&mut self, name: Token) -> usize {
+        let name = self.intern(name.content);
+        self.make_constant(Value::Str(name))
+    }
+
+    fn make_constant(&mut self, val: Value) -> usize {
+        let key = match val {
+            Value::Number(n) => Some(ConstantKey::Number(n.to_bits())),
+            Value::Int(n) => Some(ConstantKey::Int(n)),
+            Value::Str(string) => Some(ConstantKey::Str(&*string)),
+            _ => None,
+        };
+        if let Some(&idx) = key.as_ref().and_then(|key| self.state().constants.get(key)) {
+            return idx;
+        }
+
+        if self.chunk().const_pool.len() == CONSTANTS_MAX {
+            self.error(LimitExceeded, "Too many constants in one chunk");
+            return 0;
+        }
+
+        self.chunk().const_pool.push(val);
+        let idx = self.chunk().const_pool.len() - 1;
+        if let Some(key) = key {
+            self.state().constants.insert(key, idx);
+        }
+        idx
+    }
+
+    fn emit_constant(&mut self, val: Value) {
+        let idx = self.make_constant(val);
+        self.emit_constant_op(OpConstant, idx);
+    }
+
+    //for any op whose operand is a constant index, indexes past
+    //a byte get their upper bits from an OpWide in front
+    fn emit_constant_op(&mut self, op: Op, idx: usize) {
+        let [_, high, mid, low] = (idx as u32).to_be_bytes();
+        if idx > u8::MAX as usize {
+            self.emit(OpWide);
+            self.emit_byte(high);
+            self.emit_byte(mid);
+        }
+        self.emit(op);
+        self.emit_byte(low);
     }
 
     fn statement(&mut self) {
         if self.check_match(Print) {
             self.print_statement();
+        } else if self.check_match(If) {
+            self.if_statement();
+        } else if self.check_match(Return) {
+            self.return_statement();
+        } else if self.check_match(While) {
+            self.while_statement();
+        } else if self.check_match(For) {
+            self.for_statement();
+        } else if self.check_match(LeftBrace) {
+            self.begin_scope();
+            self.block();
+            self.end_scope();
+        } else {
+            self.expression_statement();
+        }
+    }
+
+    fn return_statement(&mut self) {
+        if self.state().kind == FunctionKind::Script {
+            self.error(InvalidReturn, "Can't return from top-level code");
+        }
+
+        if self.check_match(Semicolon) {
+            self.emit_return();
+        } else {
+            if self.state().kind == FunctionKind::Initializer {
+                self.error(InvalidReturn, "Can't return a value from an initializer");
+            }
+
+            self.expression();
+            self.consume(Semicolon, "Expected ';' after return value");
+            self.emit(OpReturn);
+        }
+    }
+
+    fn if_statement(&mut self) {
+        self.consume(LeftParen, "Expected '(' after 'if'");
+        let opener = self.parser.previous;
+        self.expression();
+        self.consume_closing(RightParen, "Expected ')' after condition", opener);
+
+        let then_jump = self.emit_jump(OpJumpIfFalse);
+        self.emit(OpPop);
+        self.statement();
+
+        let else_jump = self.emit_jump(OpJump);
+        self.patch_jump(then_jump);
+        //condition is left on the stack by the jump either way
+        self.emit(OpPop);
+
+        if self.check_match(Else) {
+            self.statement();
+        }
+        self.patch_jump(else_jump);
+    }
+
+    fn while_statement(&mut self) {
+        let loop_start = self.chunk().bytecode.len();
+        self.consume(LeftParen, "Expected '(' after 'while'");
+        let opener = self.parser.previous;
+        self.expression();
+        self.consume_closing(RightParen, "Expected ')' after condition", opener);
+
+        let exit_jump = self.emit_jump(OpJumpIfFalse);
+        self.emit(OpPop);
+        self.statement();
+        self.emit_loop(loop_start);
+
+        self.patch_jump(exit_jump);
+        self.emit(OpPop);
+    }
+
+    //desugared into a while loop wrapped in its own scope
+    //so the initializer variable doesn't leak out
+    fn for_statement(&mut self) {
+        self.begin_scope();
+        self.consume(LeftParen, "Expected '(' after 'for'");
+
+        if self.check_match(Semicolon) {
+            //no initializer
+        } else if self.check_match(Var) {
+            self.var_declaration();
+        } else {
+            self.expression_statement();
+        }
+
+        let mut loop_start = self.chunk().bytecode.len();
+        let mut exit_jump = None;
+        if !self.check_match(Semicolon) {
+            self.expression();
+            self.consume(Semicolon, "Expected ';' after loop condition");
+
+            exit_jump = Some(self.emit_jump(OpJumpIfFalse));
+            self.emit(OpPop);
+        }
+
+        //the increment is compiled before the body but runs after it,
+        //so jump over it now and loop back to it at the end of the body
+        if !self.check_match(RightParen) {
+            let body_jump = self.emit_jump(OpJump);
+            let increment_start = self.chunk().bytecode.len();
+            self.expression();
+            self.emit(OpPop);
+            self.consume(RightParen, "Expected ')' after for clauses");
+
+            self.emit_loop(loop_start);
+            loop_start = increment_start;
+            self.patch_jump(body_jump);
+        }
+
+        self.statement();
+        self.emit_loop(loop_start);
+
+        if let Some(exit_jump) = exit_jump {
+            self.patch_jump(exit_jump);
+            self.emit(OpPop);
+        }
+
+        self.end_scope();
+    }
+
+    //returns the offset of the placeholder operand for patch_jump
+    fn emit_jump(&mut self, op: Op) -> usize {
+        self.emit(op);
+        self.emit_byte(0xff);
+        self.emit_byte(0xff);
+        self.chunk().bytecode.len() - 2
+    }
+
+    fn patch_jump(&mut self, offset: usize) {
+        //-2 to account for the operand itself
+        let jump = self.chunk().bytecode.len() - offset - 2;
+        if jump > u16::MAX as usize {
+            self.error(LimitExceeded, "Too much code to jump over");
+            return;
+        }
+
+        self.chunk().bytecode[offset..offset + 2].copy_from_slice(&(jump as u16).to_be_bytes());
+    }
+
+    fn emit_loop(&mut self, loop_start: usize) {
+        self.emit(OpLoop);
+
+        let offset = self.chunk().bytecode.len() - loop_start + 2;
+        if offset > u16::MAX as usize {
+            self.error(LimitExceeded, "Loop body too large");
+        }
+
+        let [hi, lo] = (offset as u16).to_be_bytes();
+        self.emit_byte(hi);
+        self.emit_byte(lo);
+    }
+
+    fn block(&mut self) {
+        let opener = self.parser.previous;
+        while self.parser.current.kind != RightBrace && self.parser.current.kind != Eof {
+            self.declaration();
+        }
+
+        self.consume_closing(RightBrace, "Expected '}' after block", opener);
+    }
+
+    fn begin_scope(&mut self) {
+        self.state().scope_depth += 1;
+    }
+
+    fn end_scope(&mut self) {
+        let state = self.state();
+        state.scope_depth -= 1;
+        let scope_depth = state.scope_depth;
+
+        while let Some(local) = self
+            .state()
+            .locals
+            .pop_if(|local| local.depth.is_none_or(|depth| depth > scope_depth))
+        {
+            if local.is_captured {
+                self.emit(OpCloseUpvalue);
+            } else {
+                self.emit(OpPop);
+            }
+        }
+    }
+
+    fn expression_statement(&mut self) {
+        self.expression();
+        self.consume(Semicolon, "Expected ';' after expression");
+
+        if self.echo && self.states.len() == 1 && self.state().scope_depth == 0 {
+            self.emit(OpPrint);
+        } else {
+            self.emit(OpPop);
         }
     }
     
     fn print_statement(&mut self) {
         self.expression();
-        if self.parser.current.kind != Semicolon { panic!("Expected ';'"); }
-        self.advance();
-        self.bytecode.push(OpPrint as u8);
+        self.consume(Semicolon, "Expected ';' after value");
+        self.emit(OpPrint);
     }
 
     fn expression(&mut self) {
@@ -126,16 +967,139 @@ impl<'a> Compiler<'a> {
     
     //prolly gonna have to change this later
     fn grouping(&mut self) {
+        let opener = self.parser.previous;
         //Never be afraid to express yourself :)
         self.expression();
         //which one ?
         
+        self.consume_closing(RightParen, "Expected ')' after expression", opener);
+    }
+    
+    fn variable(&mut self, can_assign: bool) {
+        self.named_variable(self.parser.previous, can_assign);
+    }
+
+    fn named_variable(&mut self, name: Token, can_assign: bool) {
+        let current = self.states.len() - 1;
+        //slots and upvalue indexes always fit in a byte, so
+        //only globals can end up with an OpWide
+        let (get_op, set_op, arg) = if let Some(slot) = self.resolve_local(current, name) {
+            (OpGetLocal, OpSetLocal, slot as usize)
+        } else if let Some(upvalue) = self.resolve_upvalue(current, name) {
+            (OpGetUpvalue, OpSetUpvalue, upvalue as usize)
+        } else {
+            (OpGetGlobal, OpSetGlobal, self.identifier_constant(name))
+        };
+
+        if can_assign && self.check_match(Equal) {
+            self.expression();
+            self.emit_constant_op(set_op, arg);
+        } else {
+            self.emit_constant_op(get_op, arg);
+        }
+    }
+
+    fn call(&mut self) {
+        let arg_count = self.argument_list();
+        self.emit(OpCall);
+        self.emit_byte(arg_count);
+    }
+
+    fn argument_list(&mut self) -> u8 {
+        let opener = self.parser.previous;
+        let mut arg_count: u8 = 0;
         if self.parser.current.kind != RightParen {
-            panic!("Expected ')'");
+            loop {
+                self.expression();
+                if arg_count == 255 {
+                    self.error(LimitExceeded, "Can't have more than 255 arguments");
+                } else {
+                    arg_count += 1;
+                }
+
+                if !self.check_match(Comma) {
+                    break;
+                }
+            }
         }
-        self.advance();
+        self.consume_closing(RightParen, "Expected ')' after arguments", opener);
+        arg_count
     }
-    
+
+    fn dot(&mut self, can_assign: bool) {
+        self.consume(Identifier, "Expected property name after '.'");
+        let name = self.identifier_constant(self.parser.previous);
+
+        if can_assign && self.check_match(Equal) {
+            self.expression();
+            self.emit_constant_op(OpSetProperty, name);
+        } else if self.check_match(LeftParen) {
+            //calling a method straight away skips creating a bound method
+            let arg_count = self.argument_list();
+            self.emit_constant_op(OpInvoke, name);
+            self.emit_byte(arg_count);
+        } else {
+            self.emit_constant_op(OpGetProperty, name);
+        }
+    }
+
+    fn this(&mut self) {
+        if self.classes.is_empty() {
+            self.error(InvalidThis, "Can't use 'this' outside of a class");
+            return;
+        }
+
+        //can't assign to this
+        self.variable(false);
+    }
+
+    fn super_(&mut self) {
+        match self.classes.last() {
+            None => self.error(InvalidSuper, "Can't use 'super' outside of a class"),
+            Some(class) if !class.has_superclass => {
+                self.error(InvalidSuper, "Can't use 'super' in a class with no superclass")
+            }
+            _ => {}
+        }
+
+        self.consume(Dot, "Expected '.' after 'super'");
+        self.consume(Identifier, "Expected superclass method name");
+        let name = self.identifier_constant(self.parser.previous);
+
+        //the receiver goes below the superclass, which the vm pops off
+        self.named_variable(Self::synthetic_token("this"), false);
+        if self.check_match(LeftParen) {
+            let arg_count = self.argument_list();
+            self.named_variable(Self::synthetic_token("super"), false);
+            self.emit_constant_op(OpSuperInvoke, name);
+            self.emit_byte(arg_count);
+        } else {
+            self.named_variable(Self::synthetic_token("super"), false);
+            self.emit_constant_op(OpGetSuper, name);
+        }
+    }
+
+    fn and(&mut self) {
+        //left operand is falsey, so skip the right and keep it
+        let end_jump = self.emit_jump(OpJumpIfFalse);
+
+        self.emit(OpPop);
+        self.parse_precedence(Precedence::And);
+
+        self.patch_jump(end_jump);
+    }
+
+    fn or(&mut self) {
+        let else_jump = self.emit_jump(OpJumpIfFalse);
+        let end_jump = self.emit_jump(OpJump);
+
+        self.patch_jump(else_jump);
+        self.emit(OpPop);
+
+        self.parse_precedence(Precedence::Or);
+        self.patch_jump(end_jump);
+    }
+
     fn literal(&mut self) {
         let op = match self.parser.previous.kind {
             True => OpTrue,
@@ -144,26 +1108,54 @@ impl<'a> Compiler<'a> {
             _ => unreachable!(),
         };
         
-        self.bytecode.push(op as u8);
+        self.emit(op);
     }
     
     fn string(&mut self) {
-        let content = self.parser.previous.content;
-        let string = content[1..content.len() - 1].to_owned();
-        self.const_pool.push(Value::Str(string));
-        self.bytecode.push(OpConstant as u8);
-        self.bytecode.push((self.const_pool.len() - 1) as u8);
+        let token = self.parser.previous;
+        let raw = &token.content[1..token.content.len() - 1];
+        let chars = match unescape(raw) {
+            Ok(chars) => chars,
+            Err((range, msg)) => {
+                //blame just the escape, +1 for the opening quote
+                let escape = Self::sub_token(token, range.start + 1..range.end + 1);
+                self.error_at(escape, InvalidEscape, msg);
+                return;
+            }
+        };
+
+        let string = self.intern(&chars);
+        self.emit_constant(Value::Str(string));
     }
     
-    fn number(&mut self) {
-        let val = self.parser.previous.content.parse::<f64>().unwrap();
-        self.const_pool.push(Value::Number(val));
-        if self.const_pool.len() > 256 {
-            panic!("No room in const pool");
+    //a token for part of another, working out where that part starts
+    fn sub_token(token: Token<'a>, range: Range<usize>) -> Token<'a> {
+        let prefix = &token.content[..range.start];
+        let (line_num, column) = match prefix.rfind('\n') {
+            Some(idx) => (
+                token.line_num + prefix.matches('\n').count(),
+                prefix[idx + 1..].chars().count() + 1,
+            ),
+            None => (token.line_num, token.column + prefix.chars().count()),
+        };
+
+        Token {
+            kind: token.kind,
+            line_num,
+            column,
+            span: Span::new(token.span.start + range.start, token.span.start + range.end),
+            content: &token.content[range],
         }
-        
-        self.bytecode.push(OpConstant as u8);
-        self.bytecode.push((self.const_pool.len() - 1) as u8);
+    }
+
+    //literals without a '.' are ints, unless they're too big to be one
+    fn number(&mut self) {
+        let content = self.parser.previous.content;
+        let val = match content.parse::<i64>() {
+            Ok(n) => Value::Int(n),
+            Err(_) => Value::Number(content.parse::<f64>().unwrap()),
+        };
+        self.emit_constant(val);
     }
     
     //keep for now, possibly remove later
@@ -173,10 +1165,10 @@ impl<'a> Compiler<'a> {
         
         match op_type {
             Minus => {
-                self.bytecode.push(OpNegate as u8);
+                self.emit(OpNegate);
             }
             Bang => {
-                self.bytecode.push(OpNot as u8);
+                self.emit(OpNot);
             }
             _ => unreachable!(),
         };
@@ -192,104 +1184,187 @@ impl<'a> Compiler<'a> {
             Minus => OpSubtract,
             Star => OpMultiply,
             Slash => OpDivide,
-            //BE, LE, and GE are inverted later
-            BangEqual | EqualEqual => OpEqual,
-            Greater | LessEqual => OpGreater,
-            Less | GreaterEqual => OpLess,
+            TildeSlash => OpFloorDivide,
+            Percent => OpModulo,
+            //each gets its own op, `a <= b` isn't `!(a > b)` once NaN's involved
+            EqualEqual => OpEqual,
+            BangEqual => OpNotEqual,
+            Greater => OpGreater,
+            GreaterEqual => OpGreaterEqual,
+            Less => OpLess,
+            LessEqual => OpLessEqual,
             _ => unreachable!(),
         };
 
-        self.bytecode.push(op as u8);
-
-        match op_type {
-            BangEqual | GreaterEqual | LessEqual => self.bytecode.push(OpNot as u8),
-            _ => {}
-        };
+        self.emit(op);
     }
 
     //What the fuck
     fn parse_precedence(&mut self, prec_level: Precedence) {
         self.advance();
-        let prefix_rule = self.get_rule(self.parser.previous.kind).prefix;
-
-        //for some reason it won't let me cast a
-        //closure that's a local variable
-        if prefix_rule as usize == self.get_rule(Blank).prefix as usize {
-            panic!("Expected expression");
-        }
+        let Some(prefix_rule) = self.get_rule(self.parser.previous.kind).prefix else {
+            self.error(UnexpectedToken, "Expected expression");
+            return;
+        };
 
-        prefix_rule(self);
+        let can_assign = prec_level <= Assignemnt;
+        prefix_rule(self, can_assign);
 
         while prec_level <= self.get_rule(self.parser.current.kind).prec {
             self.advance();
-            let infix_rule = self.get_rule(self.parser.previous.kind).infix;
-            infix_rule(self);
+            if let Some(infix_rule) = self.get_rule(self.parser.previous.kind).infix {
+                infix_rule(self, can_assign);
+            }
+        }
+
+        //nothing consumed the '=', so whatever came before it
+        //can't be assigned to, e.g. a + b = c
+        if can_assign && self.check_match(Equal) {
+            self.error(InvalidAssignmentTarget, "Invalid assignment target");
         }
     }
 
     fn get_rule(&mut self, token_type: TokenType) -> ParseRule {
         match token_type {
             LeftParen => ParseRule {
-                prefix: |s| s.grouping(),
-                infix: |_s| {},
-                prec: Null,
+                prefix: Some(|s, _| s.grouping()),
+                infix: Some(|s, _| s.call()),
+                prec: Call,
             },
             Minus => ParseRule {
-                prefix: |s| s.unary(),
-                infix: |s| s.binary(),
+                prefix: Some(|s, _| s.unary()),
+                infix: Some(|s, _| s.binary()),
                 prec: Term,
             },
             Plus => ParseRule {
-                prefix: |_s| {},
-                infix: |s| s.binary(),
+                prefix: None,
+                infix: Some(|s, _| s.binary()),
                 prec: Term,
             },
             Slash => ParseRule {
-                prefix: |_s| {},
-                infix: |s| s.binary(),
+                prefix: None,
+                infix: Some(|s, _| s.binary()),
                 prec: Factor,
             },
-            Star => ParseRule {
-                prefix: |_s| {},
-                infix: |s| s.binary(),
+            Star | Percent | TildeSlash => ParseRule {
+                prefix: None,
+                infix: Some(|s, _| s.binary()),
                 prec: Factor,
             },
             Number => ParseRule {
-                prefix: |s| s.number(),
-                infix: |_s| {},
+                prefix: Some(|s, _| s.number()),
+                infix: None,
                 prec: Null,
             },
             True | False | Nil => ParseRule {
-                prefix: |s| s.literal(),
-                infix: |_s| {},
+                prefix: Some(|s, _| s.literal()),
+                infix: None,
                 prec: Null,
             },
             Bang => ParseRule {
-                prefix: |s| s.unary(),
-                infix: |_s| {},
+                prefix: Some(|s, _| s.unary()),
+                infix: None,
                 prec: Null,
             },
             BangEqual | EqualEqual => ParseRule {
-                prefix: |_s| {},
-                infix: |s| s.binary(),
+                prefix: None,
+                infix: Some(|s, _| s.binary()),
                 prec: Equality,
             },
             Greater | GreaterEqual | Less | LessEqual => ParseRule {
-                prefix: |_s| {},
-                infix: |s| s.binary(),
+                prefix: None,
+                infix: Some(|s, _| s.binary()),
                 prec: Comparison,
             },
             TokenType::Str => ParseRule {
-                prefix: |s| s.string(),
-                infix: |_s| {},
+                prefix: Some(|s, _| s.string()),
+                infix: None,
+                prec: Null,
+            },
+            Identifier => ParseRule {
+                prefix: Some(|s, can_assign| s.variable(can_assign)),
+                infix: None,
                 prec: Null,
             },
+            Dot => ParseRule {
+                prefix: None,
+                infix: Some(|s, can_assign| s.dot(can_assign)),
+                prec: Call,
+            },
+            Super => ParseRule {
+                prefix: Some(|s, _| s.super_()),
+                infix: None,
+                prec: Null,
+            },
+            This => ParseRule {
+                prefix: Some(|s, _| s.this()),
+                infix: None,
+                prec: Null,
+            },
+            TokenType::And => ParseRule {
+                prefix: None,
+                infix: Some(|s, _| s.and()),
+                prec: Precedence::And,
+            },
+            TokenType::Or => ParseRule {
+                prefix: None,
+                infix: Some(|s, _| s.or()),
+                prec: Precedence::Or,
+            },
 
             _ => ParseRule {
-                prefix: |_s| {},
-                infix: |_s| {},
+                prefix: None,
+                infix: None,
                 prec: Null,
             },
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compile(source: &str, vm: &mut Vm) -> Gc<Function> {
+        Compiler::new(source, vm).compile().unwrap()
+    }
+
+    #[test]
+    fn constants_are_deduped() {
+        let mut source = String::new();
+        //each literal twice over, the second lot reuses the first
+        for _ in 0..2 {
+            for n in 0..300 {
+                source += &format!("print {n};");
+            }
+            source += "print \"s\"; print 1.5;";
+        }
+        //the name's one constant for both, and 299 is already there
+        source += "var g = 299; print g;";
+
+        let mut vm = Vm::new();
+        let function = compile(&source, &mut vm);
+        assert_eq!(function.chunk.const_pool.len(), 303);
+    }
+
+    #[test]
+    fn wide_indexes_disassemble() {
+        let mut source = String::new();
+        for n in 0..300 {
+            source += &format!("print {n};\n");
+        }
+
+        let mut vm = Vm::new();
+        let disasm = compile(&source, &mut vm).chunk.to_string();
+        let lines: Vec<&str> = disasm.lines().collect();
+
+        //299 is index 299, so its OpConstant needs the upper bits from an OpWide
+        let wide = lines
+            .iter()
+            .position(|line| line.ends_with("OpConstant    299 '299'"))
+            .expect(&disasm);
+        assert!(lines[wide - 1].ends_with("OpWide    256"), "{disasm}");
+        assert!(disasm.contains("OpConstant    255 '255'"), "{disasm}");
+        assert_eq!(disasm.matches("OpWide").count(), 300 - 256);
+    }
+}
@@ -0,0 +1,248 @@
+use std::env;
+use std::fmt::Write;
+use std::io::{self, IsTerminal};
+
+//byte offsets into the source, end is exclusive
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Self {
+        Self { start, end }
+    }
+}
+
+//follow up to a diagnostic, optionally pointing at some other bit of source
+#[derive(Debug, Clone)]
+pub struct Note {
+    pub message: String,
+    pub span: Option<Span>,
+}
+
+//Anything that can go wrong before the vm runs gets turned into one
+//of these so it can be shown against the source it came from
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub message: String,
+    pub span: Span,
+    pub notes: Vec<Note>,
+}
+
+const RED: &str = "\x1b[1;31m";
+const BLUE: &str = "\x1b[1;34m";
+const CYAN: &str = "\x1b[1;36m";
+const BOLD: &str = "\x1b[1m";
+const RESET: &str = "\x1b[0m";
+
+pub struct Renderer<'a> {
+    source: &'a str,
+    color: bool,
+}
+
+impl<'a> Renderer<'a> {
+    pub fn new(source: &'a str, color: bool) -> Self {
+        Self { source, color }
+    }
+
+    //error: Expected ';' after value
+    // --> line 1:9
+    //  |
+    //1 | print 1 + 2
+    //  |         ^
+    pub fn render(&self, diag: &Diagnostic) -> String {
+        let mut out = String::new();
+
+        _ = writeln!(
+            out,
+            "{}error{}: {}{}{}",
+            self.paint(RED),
+            self.paint(RESET),
+            self.paint(BOLD),
+            diag.message,
+            self.paint(RESET)
+        );
+        self.snippet(&mut out, diag.span, '^', RED);
+
+        for note in &diag.notes {
+            _ = writeln!(
+                out,
+                "{}note{}: {}",
+                self.paint(CYAN),
+                self.paint(RESET),
+                note.message
+            );
+            if let Some(span) = note.span {
+                self.snippet(&mut out, span, '-', CYAN);
+            }
+        }
+
+        out
+    }
+
+    fn snippet(&self, out: &mut String, span: Span, marker: char, color: &'static str) {
+        let start = span.start.min(self.source.len());
+        let line_start = self.source[..start].rfind('\n').map_or(0, |idx| idx + 1);
+        let line_end = self.source[start..]
+            .find('\n')
+            .map_or(self.source.len(), |idx| start + idx);
+
+        let line_num = self.source[..start].matches('\n').count() + 1;
+        let text = self.source[line_start..line_end].trim_end_matches('\r');
+        let prefix = &self.source[line_start..start];
+        let column = prefix.chars().count() + 1;
+
+        //spans running past the end of the line only get underlined up to it
+        let end = span.end.clamp(start, line_end);
+        let width = self.source[start..end].chars().count().max(1);
+
+        //reuse the source's own tabs so the marker lines up
+        let padding: String = prefix
+            .chars()
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+
+        let gutter = " ".repeat(line_num.to_string().len());
+        let (blue, reset) = (self.paint(BLUE), self.paint(RESET));
+
+        _ = writeln!(out, "{gutter}{blue}-->{reset} line {line_num}:{column}");
+        _ = writeln!(out, "{gutter} {blue}|{reset}");
+        _ = writeln!(out, "{blue}{line_num} |{reset} {text}");
+        _ = writeln!(
+            out,
+            "{gutter} {blue}|{reset} {padding}{}{}{reset}",
+            self.paint(color),
+            marker.to_string().repeat(width)
+        );
+    }
+
+    fn paint(&self, code: &'static str) -> &'static str {
+        if self.color {
+            code
+        } else {
+            ""
+        }
+    }
+}
+
+//renders straight to stderr, in color if that's a terminal that wants it
+pub fn eprint_all(source: &str, diagnostics: impl IntoIterator<Item = Diagnostic>) {
+    let color = io::stderr().is_terminal() && env::var_os("NO_COLOR").is_none();
+    let renderer = Renderer::new(source, color);
+    for diag in diagnostics {
+        eprintln!("{}", renderer.render(&diag));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(source: &str, diag: Diagnostic) -> String {
+        Renderer::new(source, false).render(&diag)
+    }
+
+    //span of the first match of needle
+    fn span_of(source: &str, needle: &str) -> Span {
+        let start = source.find(needle).unwrap();
+        Span::new(start, start + needle.len())
+    }
+
+    fn error(message: &str, span: Span) -> Diagnostic {
+        Diagnostic {
+            message: message.to_owned(),
+            span,
+            notes: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn tabs_are_kept_so_the_marker_lines_up() {
+        let source = "fun f() {\n\t\tprint 1 +;\n}\n";
+        let out = render(source, error("Expected expression", span_of(source, ";")));
+
+        assert_eq!(
+            out,
+            "error: Expected expression\n \
+             --> line 2:12\n  \
+             |\n\
+             2 | \t\tprint 1 +;\n  \
+             | \t\t         ^\n"
+        );
+    }
+
+    #[test]
+    fn columns_and_widths_count_chars_not_bytes() {
+        let source = "var é = \"ü\" + ünknown;";
+        let out = render(source, error("Undefined variable", span_of(source, "ünknown")));
+
+        assert_eq!(
+            out,
+            "error: Undefined variable\n \
+             --> line 1:15\n  \
+             |\n\
+             1 | var é = \"ü\" + ünknown;\n  \
+             |               ^^^^^^^\n"
+        );
+    }
+
+    #[test]
+    fn error_at_end_of_file() {
+        let source = "print 1\n";
+        let end = source.len();
+        let out = render(source, error("Expected ';' after value", Span::new(end, end)));
+
+        //the last line is the empty one after the newline
+        assert_eq!(
+            out,
+            "error: Expected ';' after value\n \
+             --> line 2:1\n  \
+             |\n\
+             2 | \n  \
+             | ^\n"
+        );
+    }
+
+    #[test]
+    fn notes_get_their_own_snippet() {
+        let source = "print (1 +\n  2;";
+        let diag = Diagnostic {
+            message: "Expected ')' after expression".to_owned(),
+            span: span_of(source, ";"),
+            notes: vec![
+                Note {
+                    message: "to match this '('".to_owned(),
+                    span: Some(span_of(source, "(")),
+                },
+                Note {
+                    message: "no span on this one".to_owned(),
+                    span: None,
+                },
+            ],
+        };
+
+        assert_eq!(
+            render(source, diag),
+            "error: Expected ')' after expression\n \
+             --> line 2:4\n  \
+             |\n\
+             2 |   2;\n  \
+             |    ^\n\
+             note: to match this '('\n \
+             --> line 1:7\n  \
+             |\n\
+             1 | print (1 +\n  \
+             |       -\n\
+             note: no span on this one\n"
+        );
+    }
+
+    #[test]
+    fn color_only_when_asked_for() {
+        let diag = error("oops", Span::new(0, 1));
+        assert!(!Renderer::new("x", false).render(&diag).contains('\x1b'));
+        assert!(Renderer::new("x", true).render(&diag).contains(RED));
+    }
+}
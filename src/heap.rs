@@ -0,0 +1,214 @@
+use crate::vm::Value;
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::mem;
+use std::ops::Deref;
+use std::ptr::NonNull;
+
+//first collection happens once this much has been allocated
+const INITIAL_GC_THRESHOLD: usize = 1024 * 1024;
+const GC_HEAP_GROW_FACTOR: usize = 2;
+
+//Anything that lives on the heap has to be able to mark
+//whatever other heap objects it holds onto
+pub trait Trace {
+    fn trace(&self, tracer: &mut Tracer);
+
+    //memory owned by the object outside of its box,
+    //only used to decide when to collect
+    fn extra_size(&self) -> usize {
+        0
+    }
+}
+
+struct GcBox<T: ?Sized> {
+    marked: Cell<bool>,
+    size: usize,
+    value: T,
+}
+
+//Cheap copyable handle to an object owned by the Heap. Only valid for as
+//long as the object is reachable from the roots the vm hands to collect
+pub struct Gc<T: 'static> {
+    ptr: NonNull<GcBox<T>>,
+}
+
+impl<T> Gc<T> {
+    pub fn ptr_eq(a: Gc<T>, b: Gc<T>) -> bool {
+        a.ptr == b.ptr
+    }
+
+    //only meaningful between marking and sweeping
+    pub fn is_marked(self) -> bool {
+        unsafe { self.ptr.as_ref() }.marked.get()
+    }
+
+    //for packing a handle into something smaller than a Value
+    #[cfg_attr(not(feature = "nan_boxing"), allow(dead_code))]
+    pub(crate) fn as_raw(self) -> *const () {
+        self.ptr.as_ptr() as *const ()
+    }
+
+    //ptr has to have come from as_raw on a Gc<T> that's still alive
+    #[cfg_attr(not(feature = "nan_boxing"), allow(dead_code))]
+    pub(crate) unsafe fn from_raw(ptr: *const ()) -> Self {
+        Self {
+            ptr: NonNull::new_unchecked(ptr as *mut GcBox<T>),
+        }
+    }
+}
+
+impl<T> Clone for Gc<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for Gc<T> {}
+
+impl<T> Deref for Gc<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        //the collector never frees anything reachable, and everything
+        //the vm can still get a handle to is reachable
+        unsafe { &self.ptr.as_ref().value }
+    }
+}
+
+impl<T: fmt::Display> fmt::Display for Gc<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+//don't recurse into the object, it's probably cyclic
+impl<T> fmt::Debug for Gc<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Gc({:p})", self.ptr)
+    }
+}
+
+pub struct Tracer {
+    gray: Vec<NonNull<GcBox<dyn Trace>>>,
+}
+
+impl Tracer {
+    pub fn mark<T: Trace>(&mut self, gc: Gc<T>) {
+        let obj = unsafe { gc.ptr.as_ref() };
+        if obj.marked.get() {
+            return;
+        }
+
+        obj.marked.set(true);
+        self.gray.push(gc.ptr);
+    }
+
+    pub fn mark_value(&mut self, val: &Value) {
+        match val {
+            Value::Str(obj) => self.mark(*obj),
+            Value::Function(obj) => self.mark(*obj),
+            Value::Closure(obj) => self.mark(*obj),
+            Value::Class(obj) => self.mark(*obj),
+            Value::Instance(obj) => self.mark(*obj),
+            Value::BoundMethod(obj) => self.mark(*obj),
+            Value::Native(obj) => self.mark(*obj),
+            Value::List(obj) => self.mark(*obj),
+            Value::Bool(_) | Value::Nil | Value::Number(_) | Value::Int(_) => {}
+        }
+    }
+}
+
+pub struct Heap {
+    objects: Vec<NonNull<GcBox<dyn Trace>>>,
+    bytes_allocated: usize,
+    next_gc: usize,
+    //collect on every allocation, shakes out missing roots
+    pub stress: bool,
+}
+
+impl Heap {
+    pub fn new() -> Self {
+        Self {
+            objects: Vec::new(),
+            bytes_allocated: 0,
+            next_gc: INITIAL_GC_THRESHOLD,
+            stress: cfg!(feature = "stress_gc"),
+        }
+    }
+
+    //never collects by itself, the caller decides when
+    //it's safe to by checking should_collect first
+    pub(crate) fn alloc<T: Trace>(&mut self, value: T) -> Gc<T> {
+        let size = mem::size_of::<GcBox<T>>() + value.extra_size();
+        let boxed = Box::new(GcBox {
+            marked: Cell::new(false),
+            size,
+            value,
+        });
+
+        let ptr = NonNull::from(Box::leak(boxed));
+        self.objects.push(ptr);
+        self.bytes_allocated += size;
+
+        Gc { ptr }
+    }
+
+    pub fn should_collect(&self) -> bool {
+        self.stress || self.bytes_allocated > self.next_gc
+    }
+
+    //mark_roots has to mark everything that's still reachable, whatever
+    //it misses gets freed. remove_weak runs after marking so anything
+    //holding weak references can drop the ones that are about to die
+    pub(crate) fn collect(&mut self, mark_roots: impl FnOnce(&mut Tracer), remove_weak: impl FnOnce()) {
+        let mut tracer = Tracer { gray: Vec::new() };
+        mark_roots(&mut tracer);
+
+        while let Some(obj) = tracer.gray.pop() {
+            unsafe { obj.as_ref() }.value.trace(&mut tracer);
+        }
+
+        remove_weak();
+        self.sweep();
+        self.next_gc = (self.bytes_allocated * GC_HEAP_GROW_FACTOR).max(INITIAL_GC_THRESHOLD);
+    }
+
+    fn sweep(&mut self) {
+        let mut freed = 0;
+
+        self.objects.retain(|obj| {
+            let gc_box = unsafe { obj.as_ref() };
+            if gc_box.marked.get() {
+                gc_box.marked.set(false);
+                return true;
+            }
+
+            freed += gc_box.size;
+            drop(unsafe { Box::from_raw(obj.as_ptr()) });
+            false
+        });
+
+        self.bytes_allocated -= freed;
+    }
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for Heap {
+    fn drop(&mut self) {
+        for obj in self.objects.drain(..) {
+            drop(unsafe { Box::from_raw(obj.as_ptr()) });
+        }
+    }
+}
+
+impl<T: Trace> Trace for RefCell<T> {
+    fn trace(&self, tracer: &mut Tracer) {
+        self.borrow().trace(tracer);
+    }
+}
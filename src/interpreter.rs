@@ -0,0 +1,357 @@
+use crate::compile::{CompileError, Compiler};
+use crate::heap::Gc;
+use crate::object::{Function, HostFn, NativeFunction};
+use crate::vm::{RuntimeError, Value, Vm, VmError};
+use std::cell::RefCell;
+use std::fmt;
+use std::io::{self, BufRead, Write};
+use std::rc::Rc;
+
+//Everything an embedder needs: run some source, then poke at its globals.
+//Globals and heap objects persist across calls to eval
+pub struct Interpreter {
+    vm: Vm,
+    echo: bool,
+}
+
+//Values handed across the embedding api. Vm values can point into the
+//heap and die at the next collection, so these own their contents instead
+#[derive(Debug, Clone, PartialEq)]
+pub enum OwnedValue {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    Int(i64),
+    Str(String),
+    //functions, classes and instances can't leave the vm, they
+    //come out as what print would show and can't be passed back in
+    Object(String),
+}
+
+//A Write that keeps everything written to it. Clones share the same
+//buffer, so hand one to set_output and keep another to read it back
+#[derive(Debug, Clone, Default)]
+pub struct OutputBuffer {
+    bytes: Rc<RefCell<Vec<u8>>>,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Compile(Vec<CompileError>),
+    Runtime(RuntimeError),
+    UndefinedGlobal(String),
+    //a value wasn't the type it needed to be to convert
+    Conversion {
+        expected: &'static str,
+        found: &'static str,
+    },
+    //too many arguments for a single call
+    TooManyArguments,
+}
+
+impl Interpreter {
+    pub fn new() -> Self {
+        Self {
+            vm: Vm::new(),
+            echo: false,
+        }
+    }
+
+    pub fn eval(&mut self, source: &str) -> Result<(), Error> {
+        let function = self.compile(source)?;
+        self.vm.interpret(function)?;
+        Ok(())
+    }
+
+    //compiles without running anything
+    pub fn check(&mut self, source: &str) -> Result<(), Error> {
+        self.compile(source).map(|_| ())
+    }
+
+    //what `kara disasm` prints, the bytecode source compiles to
+    pub fn disassemble(&mut self, source: &str) -> Result<String, Error> {
+        let function = self.compile(source)?;
+        Ok(function.chunk.to_string())
+    }
+
+    pub fn call_function(&mut self, name: &str, args: &[OwnedValue]) -> Result<OwnedValue, Error> {
+        let arg_count = u8::try_from(args.len()).map_err(|_| Error::TooManyArguments)?;
+        let callee = self
+            .vm
+            .get_global(name)
+            .ok_or_else(|| Error::UndefinedGlobal(name.to_owned()))?;
+
+        //everything goes straight onto the stack so converting
+        //one argument can't collect the ones before it
+        self.vm.push(callee);
+        for arg in args {
+            match vm_value(&mut self.vm, arg) {
+                Ok(val) => self.vm.push(val),
+                Err(err) => {
+                    self.vm.stack.clear();
+                    return Err(err);
+                }
+            }
+        }
+
+        let result = self.vm.call_from_host(arg_count)?;
+        Ok(owned_value(result))
+    }
+
+    //print goes to stdout unless this says otherwise
+    pub fn set_output(&mut self, output: impl Write + 'static) {
+        self.vm.set_output(output);
+    }
+
+    //what input() reads lines from, stdin by default
+    pub fn set_input(&mut self, input: impl BufRead + 'static) {
+        self.vm.set_input(input);
+    }
+
+    //for a repl, eval prints the value of top level expression
+    //statements instead of dropping it, so `1 + 2;` shows 3
+    pub fn set_echo_expressions(&mut self, echo: bool) {
+        self.echo = echo;
+    }
+
+    //collect on every allocation, slow but shakes out anything
+    //that isn't being kept alive properly
+    pub fn set_stress_gc(&mut self, stress: bool) {
+        self.vm.set_stress_gc(stress);
+    }
+
+    //the vm checks the arity before calling, the types are up to the
+    //native. An Err becomes a runtime error with that message
+    pub fn define_native(&mut self, name: &str, arity: u8, function: HostFn) {
+        self.vm.define_native_function(name, arity, NativeFunction::Host(function));
+    }
+
+    pub fn set_global(&mut self, name: &str, val: impl Into<OwnedValue>) -> Result<(), Error> {
+        let val = vm_value(&mut self.vm, &val.into())?;
+        self.vm.set_global(name, val);
+        Ok(())
+    }
+
+    fn compile(&mut self, source: &str) -> Result<Gc<Function>, Error> {
+        let mut compiler = Compiler::new(source, &mut self.vm);
+        if self.echo {
+            compiler = compiler.echo_expressions();
+        }
+        compiler.compile().map_err(Error::Compile)
+    }
+
+    pub fn get_global(&self, name: &str) -> Result<OwnedValue, Error> {
+        self.vm
+            .get_global(name)
+            .map(owned_value)
+            .ok_or_else(|| Error::UndefinedGlobal(name.to_owned()))
+    }
+}
+
+//the vm uses these too, for host natives
+pub(crate) fn vm_value(vm: &mut Vm, val: &OwnedValue) -> Result<Value, Error> {
+    Ok(match val {
+        OwnedValue::Nil => Value::Nil,
+        OwnedValue::Bool(b) => Value::Bool(*b),
+        OwnedValue::Number(n) => Value::Number(*n),
+        OwnedValue::Int(n) => Value::Int(*n),
+        OwnedValue::Str(chars) => Value::Str(vm.intern(chars)),
+        OwnedValue::Object(_) => {
+            return Err(Error::Conversion {
+                expected: "nil, bool, number or string",
+                found: val.type_name(),
+            })
+        }
+    })
+}
+
+pub(crate) fn owned_value(val: Value) -> OwnedValue {
+    match val {
+        Value::Nil => OwnedValue::Nil,
+        Value::Bool(b) => OwnedValue::Bool(b),
+        Value::Number(n) => OwnedValue::Number(n),
+        Value::Int(n) => OwnedValue::Int(n),
+        Value::Str(string) => OwnedValue::Str(string.chars.clone()),
+        other => OwnedValue::Object(other.to_string()),
+    }
+}
+
+impl Default for Interpreter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl OutputBuffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    //everything written so far, invalid utf-8 gets replaced
+    pub fn contents(&self) -> String {
+        String::from_utf8_lossy(&self.bytes.borrow()).into_owned()
+    }
+
+    pub fn clear(&self) {
+        self.bytes.borrow_mut().clear();
+    }
+}
+
+impl Write for OutputBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.bytes.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl OwnedValue {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            OwnedValue::Nil => "nil",
+            OwnedValue::Bool(_) => "bool",
+            OwnedValue::Number(_) | OwnedValue::Int(_) => "number",
+            OwnedValue::Str(_) => "string",
+            OwnedValue::Object(_) => "object",
+        }
+    }
+}
+
+impl From<()> for OwnedValue {
+    fn from(_: ()) -> Self {
+        OwnedValue::Nil
+    }
+}
+
+impl From<bool> for OwnedValue {
+    fn from(b: bool) -> Self {
+        OwnedValue::Bool(b)
+    }
+}
+
+impl From<f64> for OwnedValue {
+    fn from(n: f64) -> Self {
+        OwnedValue::Number(n)
+    }
+}
+
+impl From<i32> for OwnedValue {
+    fn from(n: i32) -> Self {
+        OwnedValue::Int(n.into())
+    }
+}
+
+impl From<i64> for OwnedValue {
+    fn from(n: i64) -> Self {
+        OwnedValue::Int(n)
+    }
+}
+
+impl From<&str> for OwnedValue {
+    fn from(chars: &str) -> Self {
+        OwnedValue::Str(chars.to_owned())
+    }
+}
+
+impl From<String> for OwnedValue {
+    fn from(chars: String) -> Self {
+        OwnedValue::Str(chars)
+    }
+}
+
+impl<T: Into<OwnedValue>> From<Option<T>> for OwnedValue {
+    fn from(val: Option<T>) -> Self {
+        val.map_or(OwnedValue::Nil, Into::into)
+    }
+}
+
+impl TryFrom<OwnedValue> for () {
+    type Error = Error;
+
+    fn try_from(val: OwnedValue) -> Result<Self, Error> {
+        match val {
+            OwnedValue::Nil => Ok(()),
+            other => Err(Error::Conversion { expected: "nil", found: other.type_name() }),
+        }
+    }
+}
+
+impl TryFrom<OwnedValue> for bool {
+    type Error = Error;
+
+    fn try_from(val: OwnedValue) -> Result<Self, Error> {
+        match val {
+            OwnedValue::Bool(b) => Ok(b),
+            other => Err(Error::Conversion { expected: "bool", found: other.type_name() }),
+        }
+    }
+}
+
+impl TryFrom<OwnedValue> for f64 {
+    type Error = Error;
+
+    fn try_from(val: OwnedValue) -> Result<Self, Error> {
+        match val {
+            OwnedValue::Number(n) => Ok(n),
+            OwnedValue::Int(n) => Ok(n as f64),
+            other => Err(Error::Conversion { expected: "number", found: other.type_name() }),
+        }
+    }
+}
+
+//only ints, a float would have to be rounded
+impl TryFrom<OwnedValue> for i64 {
+    type Error = Error;
+
+    fn try_from(val: OwnedValue) -> Result<Self, Error> {
+        match val {
+            OwnedValue::Int(n) => Ok(n),
+            other => Err(Error::Conversion { expected: "int", found: other.type_name() }),
+        }
+    }
+}
+
+impl TryFrom<OwnedValue> for String {
+    type Error = Error;
+
+    fn try_from(val: OwnedValue) -> Result<Self, Error> {
+        match val {
+            OwnedValue::Str(chars) => Ok(chars),
+            other => Err(Error::Conversion { expected: "string", found: other.type_name() }),
+        }
+    }
+}
+
+impl From<VmError> for Error {
+    fn from(err: VmError) -> Self {
+        match err {
+            VmError::CompileError(errors) => Error::Compile(errors),
+            VmError::RuntimeError(err) => Error::Runtime(err),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Compile(errors) => {
+                for (i, err) in errors.iter().enumerate() {
+                    if i > 0 {
+                        writeln!(f)?;
+                    }
+                    write!(f, "{err}")?;
+                }
+                Ok(())
+            }
+            Error::Runtime(err) => write!(f, "{err}"),
+            Error::UndefinedGlobal(name) => write!(f, "Undefined variable '{name}'"),
+            Error::Conversion { expected, found } => write!(f, "Expected {expected} but found {found}"),
+            Error::TooManyArguments => write!(f, "Can't pass more than 255 arguments"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
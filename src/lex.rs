@@ -1,4 +1,9 @@
+use crate::compile::{CompileError, CompileErrorKind};
+use crate::diagnostic::{Note, Span};
 use crate::vm::VmError;
+use std::mem;
+use std::ops::Range;
+use unicode_ident::{is_xid_continue, is_xid_start};
 use strum_macros::FromRepr;
 use TokenType::*;
 
@@ -6,7 +11,9 @@ use TokenType::*;
 pub struct Token<'a> {
     pub kind: TokenType,
     pub line_num: usize,
-    //How to make this an iterator over
+    //1-based, counted in chars from the start of the line
+    pub column: usize,
+    pub span: Span,
     pub content: &'a str,
 }
 
@@ -26,6 +33,7 @@ pub enum TokenType {
     Semicolon,
     Slash,
     Star,
+    Percent,
     // One or two character s.
     Bang,
     BangEqual,
@@ -35,6 +43,8 @@ pub enum TokenType {
     GreaterEqual,
     Less,
     LessEqual,
+    //`//` is already a comment, so integer division borrows Dart's spelling
+    TildeSlash,
     // Literals; Might change the implementation of these later
     //to utilize the way Clox stores literals
     Identifier,
@@ -62,82 +72,57 @@ pub enum TokenType {
     Newline,
     Eof,
     Blank,
+    //whatever the scanner choked on, the actual error is kept by the scanner
+    Error,
 
 }
 
-pub fn lex(source: &str) -> Result<Vec<Token>, VmError> {
-    let mut line_num = 0;
-    let mut iter = source.chars().peekable();
-
-    let mut tokens = Vec::new();
-    let mut curr_idx: usize = 0;
+//Hands out tokens one at a time as the compiler asks for them.
+//Everything is tracked in byte offsets so tokens can borrow straight
+//out of the source. Columns are counted in chars as the scanner goes,
+//so a really long line doesn't have to be counted over for every token
+pub struct Scanner<'a> {
+    source: &'a str,
+    //start of the token being scanned
+    start: usize,
+    current: usize,
+    line_num: usize,
+    //chars since the start of the line, up to current and up to start
+    column: usize,
+    start_column: usize,
+    errors: Vec<CompileError>,
+    done: bool,
+}
 
-    while let Some(c) = iter.next() {
-        if c.is_whitespace() {
-            if c == '\n' {
-                line_num += 1;
-            }
-            curr_idx += 1;
-            continue;
+impl<'a> Scanner<'a> {
+    pub fn new(source: &'a str) -> Self {
+        Self {
+            source,
+            start: 0,
+            current: 0,
+            line_num: 1,
+            column: 0,
+            start_column: 0,
+            errors: Vec::new(),
+            done: false,
         }
+    }
 
-        assert_eq!(source.as_bytes()[curr_idx] as char, c);
-
-        //Handle compile time errors later
-        //who needs error handling anyways
-        let start_idx = curr_idx;
-
-        let token_type = match c {
-            '>' => match iter.peek() {
-                Some('=') => {
-                    iter.next();
-                    curr_idx += 1;
-                    GreaterEqual
-                }
-                _ => Greater,
-            },
-
-            '<' => match iter.peek() {
-                Some('=') => {
-                    iter.next();
-                    curr_idx += 1;
-                    LessEqual
-                }
-                _ => Less,
-            },
-
-            '=' => match iter.peek() {
-                Some('=') => {
-                    iter.next();
-                    curr_idx += 1;
-                    EqualEqual
-                }
-                _ => Equal,
-            },
-
-            '!' => match iter.peek() {
-                Some('=') => {
-                    iter.next();
-                    curr_idx += 1;
-                    BangEqual
-                }
-                _ => Bang,
-            },
-
-            '+' => Plus,
-            '-' => Minus,
-            '*' => Star,
+    //errors found since the last call, one per Error token handed out
+    pub fn take_errors(&mut self) -> Vec<CompileError> {
+        mem::take(&mut self.errors)
+    }
 
-            '/' => match iter.peek() {
-                Some(&'/') => {
-                    while iter.next() != Some('\n') {}
-                    line_num += 1;
-                    continue;
-                }
+    fn scan_token(&mut self) -> Token<'a> {
+        self.skip_whitespace();
+        self.start = self.current;
+        self.start_column = self.column;
 
-                _ => Slash,
-            },
+        let Some(c) = self.advance() else {
+            return self.make_token(Eof);
+        };
 
+        let kind = match c {
             '(' => LeftParen,
             ')' => RightParen,
             '{' => LeftBrace,
@@ -145,117 +130,305 @@ pub fn lex(source: &str) -> Result<Vec<Token>, VmError> {
             ',' => Comma,
             '.' => Dot,
             ';' => Semicolon,
+            '+' => Plus,
+            '-' => Minus,
+            '*' => Star,
+            '/' => Slash,
+            '%' => Percent,
+            '~' if self.check_match('/') => TildeSlash,
+            '!' if self.check_match('=') => BangEqual,
+            '!' => Bang,
+            '=' if self.check_match('=') => EqualEqual,
+            '=' => Equal,
+            '>' if self.check_match('=') => GreaterEqual,
+            '>' => Greater,
+            '<' if self.check_match('=') => LessEqual,
+            '<' => Less,
+            '"' => return self.string(),
+            c if c.is_ascii_digit() => return self.number(),
+            c if is_xid_start(c) || c == '_' => return self.identifier(),
+            c => {
+                return self.error_token(
+                    CompileErrorKind::UnexpectedCharacter,
+                    &format!("Unexpected character '{c}'"),
+                    self.current,
+                    Vec::new(),
+                )
+            }
+        };
 
-            '\"' => {
-                iter.next();
-                curr_idx += 1;
+        self.make_token(kind)
+    }
 
-                //Might crash if you get to the end
-                let mut chr = iter.next();
-                while chr.is_some() && chr != Some('\"') {
-                    curr_idx += 1;
-                    chr = iter.next();
+    fn skip_whitespace(&mut self) {
+        while let Some(c) = self.peek() {
+            match c {
+                '\n' => {
+                    self.advance();
+                    self.new_line();
                 }
+                c if c.is_whitespace() => {
+                    self.advance();
+                }
+                '/' if self.peek_next() == Some('/') => {
+                    //the newline is left for the next time round
+                    while self.peek().is_some_and(|c| c != '\n') {
+                        self.advance();
+                    }
+                }
+                _ => return,
+            }
+        }
+    }
 
-                curr_idx += 1;
-
-                //Do nothing with it for now
-
-                if chr.is_none() {
-                    panic!(
-                        "Hahaha sucker, not gonna tell you what the error here is, \
-                    		fuck you and good luck debugging lmao"
+    fn string(&mut self) -> Token<'a> {
+        //the token is reported on the line it started on
+        let line_num = self.line_num;
+
+        loop {
+            match self.advance() {
+                Some('"') => break,
+                //strings can span lines
+                Some('\n') => self.new_line(),
+                //escapes are only checked when the string gets compiled,
+                //here it's just making sure \" doesn't end the string
+                Some('\\') => {
+                    if self.advance() == Some('\n') {
+                        self.new_line();
+                    }
+                }
+                Some(_) => {}
+                None => {
+                    let token = self.make_token_at(Error, line_num);
+                    //point at the opening quote, the end of the file isn't much help
+                    return self.error(
+                        token,
+                        CompileErrorKind::UnterminatedString,
+                        "Unterminated string",
+                        self.start + 1,
+                        vec![Note {
+                            message: "string runs to the end of the file".to_owned(),
+                            span: None,
+                        }],
                     );
                 }
+            }
+        }
+
+        self.make_token_at(Str, line_num)
+    }
 
-                Str
+    fn number(&mut self) -> Token<'a> {
+        self.digits();
+
+        if self.check_match('.') {
+            //`1.` isn't a number, and there's no such thing as a method on one
+            if !self.peek().is_some_and(|c| c.is_ascii_digit()) {
+                return self.error_token(
+                    CompileErrorKind::MalformedNumber,
+                    "Expected digits after '.' in number",
+                    self.current,
+                    Vec::new(),
+                );
             }
+            self.digits();
+        }
 
-            c if c.is_ascii_digit() => {
-                while let Some(next) = iter.peek() {
-                    //in case code ends with number
-                    if next.to_digit(10).is_none() {
-                        break;
-                    };
+        self.make_token(Number)
+    }
 
-                    iter.next();
-                    curr_idx += 1;
-                }
+    fn digits(&mut self) {
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.advance();
+        }
+    }
 
-                if iter.peek() == Some(&'.') {
-                    iter.next();
-                    curr_idx += 1;
-                }
+    fn identifier(&mut self) -> Token<'a> {
+        while self.peek().is_some_and(is_xid_continue) {
+            self.advance();
+        }
 
-                while let Some(next) = iter.peek() {
-                    if next.to_digit(10).is_none() {
-                        break;
-                    };
+        let kind = match &self.source[self.start..self.current] {
+            "and" => And,
+            "class" => Class,
+            "else" => Else,
+            "false" => False,
+            "for" => For,
+            "fun" => Fun,
+            "if" => If,
+            "nil" => Nil,
+            "or" => Or,
+            "print" => Print,
+            "return" => Return,
+            "super" => Super,
+            "this" => This,
+            "true" => True,
+            "var" => Var,
+            "while" => While,
+            _ => Identifier,
+        };
 
-                    iter.next();
-                    curr_idx += 1;
-                }
+        self.make_token(kind)
+    }
 
-                Number
-            }
+    fn peek(&self) -> Option<char> {
+        self.source[self.current..].chars().next()
+    }
 
-            c if c.is_alphabetic() || c == '_' => {
-                let mut lexeme = String::from(c);
+    fn peek_next(&self) -> Option<char> {
+        let mut chars = self.source[self.current..].chars();
+        chars.next();
+        chars.next()
+    }
+
+    fn advance(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.current += c.len_utf8();
+        self.column += 1;
+        Some(c)
+    }
+
+    fn check_match(&mut self, expected: char) -> bool {
+        if self.peek() != Some(expected) {
+            return false;
+        }
+        self.advance();
+        true
+    }
 
-                while let Some(c) = iter.peek() {
-                    if (*c).is_alphabetic() || (*c) == '_' {
-                        lexeme.push(*c);
-                        iter.next();
-                        curr_idx += 1;
-                    } else {
+    //call right after consuming a '\n'
+    fn new_line(&mut self) {
+        self.line_num += 1;
+        self.column = 0;
+    }
+
+    fn make_token(&self, kind: TokenType) -> Token<'a> {
+        self.make_token_at(kind, self.line_num)
+    }
+
+    //line_num is the line the token starts on
+    fn make_token_at(&self, kind: TokenType, line_num: usize) -> Token<'a> {
+        Token {
+            kind,
+            line_num,
+            column: self.start_column + 1,
+            span: Span::new(self.start, self.current),
+            content: &self.source[self.start..self.current],
+        }
+    }
+
+    fn error_token(
+        &mut self,
+        kind: CompileErrorKind,
+        msg: &str,
+        span_end: usize,
+        notes: Vec<Note>,
+    ) -> Token<'a> {
+        let token = self.make_token(Error);
+        self.error(token, kind, msg, span_end, notes)
+    }
+
+    //the error's span runs from the start of the token to span_end
+    fn error(
+        &mut self,
+        token: Token<'a>,
+        kind: CompileErrorKind,
+        msg: &str,
+        span_end: usize,
+        notes: Vec<Note>,
+    ) -> Token<'a> {
+        self.errors.push(CompileError {
+            kind,
+            message: msg.to_owned(),
+            line: token.line_num,
+            column: token.column,
+            span: Span::new(self.start, span_end),
+            //lexer errors aren't at a token, there isn't one
+            lexeme: None,
+            notes,
+        });
+        token
+    }
+}
+
+//hands out a single Eof at the end and then stops
+impl<'a> Iterator for Scanner<'a> {
+    type Item = Token<'a>;
+
+    fn next(&mut self) -> Option<Token<'a>> {
+        if self.done {
+            return None;
+        }
+
+        let token = self.scan_token();
+        self.done = token.kind == Eof;
+        Some(token)
+    }
+}
+
+//Decodes the escapes in the contents of a string literal. On failure
+//returns where the bad escape is, relative to the start of raw
+pub fn unescape(raw: &str) -> Result<String, (Range<usize>, &'static str)> {
+    let mut out = String::with_capacity(raw.len());
+    let mut chars = raw.char_indices().peekable();
+
+    while let Some((start, c)) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+
+        let escaped = match chars.next() {
+            Some((_, 'n')) => '\n',
+            Some((_, 't')) => '\t',
+            Some((_, '"')) => '"',
+            Some((_, '\\')) => '\\',
+            Some((_, 'u')) => {
+                //\u{XXXX}, up to six hex digits
+                if chars.next_if(|&(_, c)| c == '{').is_none() {
+                    let end = chars.peek().map_or(raw.len(), |&(idx, _)| idx);
+                    return Err((start..end, "Expected '{' after '\\u'"));
+                }
+
+                let mut code = 0u32;
+                let mut digits = 0;
+                while let Some((_, digit)) = chars.next_if(|&(_, c)| c.is_ascii_hexdigit()) {
+                    code = code * 16 + digit.to_digit(16).unwrap();
+                    digits += 1;
+                    if digits > 6 {
                         break;
                     }
                 }
 
-                match lexeme.as_str() {
-                    "and" => And,
-                    "class" => Class,
-                    "else" => Else,
-                    "false" => False,
-                    "for" => For,
-                    "fun" => Fun,
-                    "if" => If,
-                    "nil" => Nil,
-                    "or" => Or,
-                    "print" => Print,
-                    "return" => Return,
-                    "super" => Super,
-                    "this" => This,
-                    "true" => True,
-                    "var" => Var,
-                    "while" => While,
-                    //idfk how we're gonna handle strings lol
-                    _ => Identifier,
+                let closed = digits <= 6 && chars.next_if(|&(_, c)| c == '}').is_some();
+                let end = chars.peek().map_or(raw.len(), |&(idx, _)| idx);
+                if !closed || digits == 0 {
+                    return Err((start..end, "Unicode escape must be '\\u{' then 1 to 6 hex digits then '}'"));
+                }
+                match char::from_u32(code) {
+                    Some(c) => c,
+                    None => return Err((start..end, "Unicode escape isn't a valid character")),
                 }
             }
-
-            _ => panic!(),
+            Some((idx, c)) => {
+                return Err((start..idx + c.len_utf8(), "Invalid escape sequence"));
+            }
+            None => return Err((start..raw.len(), "Invalid escape sequence")),
         };
+        out.push(escaped);
+    }
 
-        curr_idx += 1;
-
-        let token = Token {
-            kind: token_type,
-            line_num: line_num,
-            content: &source[start_idx..curr_idx],
-        };
+    Ok(out)
+}
 
-        println!("{}\t{:?}", token.content, token.kind);
+//scans the whole source up front, for when the tokens themselves are wanted
+pub fn lex(source: &str) -> Result<Vec<Token<'_>>, VmError> {
+    let mut scanner = Scanner::new(source);
+    let tokens: Vec<_> = scanner.by_ref().filter(|token| token.kind != Error).collect();
 
-        tokens.push(token);
+    let errors = scanner.take_errors();
+    if !errors.is_empty() {
+        return Err(VmError::CompileError(errors));
     }
-
-    //Must alter once you start reading into multiple chunks
-    tokens.push(Token {
-        kind: Eof,
-        line_num: line_num,
-        content: "",
-    });
-
     Ok(tokens)
 }
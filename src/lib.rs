@@ -0,0 +1,23 @@
+//Kara, a bytecode vm for Lox following the second half of Crafting Interpreters.
+//Interpreter is what embedders want, everything that hands out heap objects
+//stays inside the crate since a handle is only valid until the next collection.
+//The lexer and diagnostics don't touch the heap, so they're public for the
+//kara binary and anyone who wants them separately
+
+mod compile;
+pub mod diagnostic;
+mod heap;
+mod interpreter;
+pub mod lex;
+//only used for the stack with the nan_boxing feature on
+#[cfg_attr(not(feature = "nan_boxing"), allow(dead_code))]
+mod nanbox;
+mod native;
+mod object;
+mod table;
+mod vm;
+
+pub use compile::{CompileError, CompileErrorKind};
+pub use interpreter::{Error, Interpreter, OutputBuffer, OwnedValue};
+pub use lex::*;
+pub use vm::{RuntimeError, TraceFrame};
@@ -1,37 +1,113 @@
 use std::env;
 use std::fs;
+use std::io::{self, IsTerminal};
 
-pub mod compile;
-pub mod lex;
-pub mod vm;
+mod repl;
 
-use compile::*;
-use lex::*;
-use vm::*;
+use kara::diagnostic::{self, Diagnostic};
+use kara::{CompileError, Error, Interpreter, Scanner, TokenType};
+
+const USAGE: &str = "\
+Usage: kara [command] [path]
+
+Commands:
+    run      run a script (the default)
+    tokens   print every token the lexer produces
+    disasm   print the compiled bytecode
+    check    compile without running, only reporting errors
+
+With no arguments at all kara starts a repl, unless stdin isn't a terminal.
+If path is missing or '-' the source is read from stdin.";
+
+#[derive(PartialEq)]
+enum Command {
+    Run,
+    Tokens,
+    Disasm,
+    Check,
+}
 
 fn main() {
-    let args: Vec<_> = env::args().collect();
+    let mut args: Vec<_> = env::args().skip(1).collect();
+    if args.is_empty() {
+        //anything piped in gets run like a script
+        if io::stdin().is_terminal() {
+            return repl::run();
+        }
+        args.push("-".to_owned());
+    }
 
-    if args.len() != 2 {
-        panic!("Expected filename");
+    if args[0] == "-h" || args[0] == "--help" {
+        println!("{USAGE}");
+        return;
     }
 
-    let source = fs::read_to_string(&args[1]).expect("Error: unable to read file");
-    let tokens = lex(&source).unwrap();
-    let mut compiler = Compiler::new(tokens);
-    compiler.compile();
+    //`kara script.lox` is short for `kara run script.lox`
+    let (command, rest) = match args[0].as_str() {
+        "run" => (Command::Run, &args[1..]),
+        "tokens" => (Command::Tokens, &args[1..]),
+        "disasm" => (Command::Disasm, &args[1..]),
+        "check" => (Command::Check, &args[1..]),
+        _ if args.len() == 1 => (Command::Run, &args[..]),
+        other => usage_error(&format!("unknown command '{other}'")),
+    };
+    if rest.len() > 1 {
+        usage_error("too many arguments");
+    }
+    let path = rest.first().map(String::as_str);
+
+    let source = read_source(path);
+
+    if command == Command::Tokens {
+        let mut scanner = Scanner::new(&source);
+        for token in scanner.by_ref() {
+            if !matches!(token.kind, TokenType::Eof | TokenType::Error) {
+                println!("{}\t{:?}", token.content, token.kind);
+            }
+        }
 
-    let mut chunk = Chunk {
-        bytecode: compiler.bytecode.clone(),
-        const_pool: compiler.const_pool.clone(),
-        //lines: Vec::new(),
+        let errors = scanner.take_errors();
+        if !errors.is_empty() {
+            report_compile_errors(&source, &errors);
+        }
+        return;
+    }
+
+    let mut interpreter = Interpreter::new();
+    let result = match command {
+        Command::Disasm => interpreter.disassemble(&source).map(|disasm| print!("{disasm}")),
+        Command::Check => interpreter.check(&source),
+        _ => interpreter.eval(&source),
     };
 
-    //chunk.bytecode.push(Op::OpReturn as u8);
+    match result {
+        Ok(()) => {}
+        Err(Error::Compile(errors)) => report_compile_errors(&source, &errors),
+        Err(err) => {
+            eprintln!("{err}");
+            std::process::exit(70);
+        }
+    }
+}
 
-    println!("{}", chunk);
+fn read_source(path: Option<&str>) -> String {
+    let result = match path {
+        None | Some("-") => io::read_to_string(io::stdin()),
+        Some(path) => fs::read_to_string(path),
+    };
+
+    result.unwrap_or_else(|err| {
+        eprintln!("Error: unable to read {}: {err}", path.unwrap_or("stdin"));
+        std::process::exit(66);
+    })
+}
+
+fn usage_error(msg: &str) -> ! {
+    eprintln!("Error: {msg}\n\n{USAGE}");
+    std::process::exit(64);
+}
 
-    let mut vm = Vm::new();
-    vm.interpret(&chunk);
-    //println!("{}", vm.interpret(&chunk));
+fn report_compile_errors(source: &str, errors: &[CompileError]) -> ! {
+    diagnostic::eprint_all(source, errors.iter().map(Diagnostic::from));
+    std::process::exit(65);
 }
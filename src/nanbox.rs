@@ -0,0 +1,180 @@
+use crate::heap::{Gc, Heap, Trace, Tracer};
+use crate::vm::Value;
+
+#[cfg(all(feature = "nan_boxing", not(target_pointer_width = "64")))]
+compile_error!("nan_boxing needs 64 bit pointers");
+
+//What the vm keeps in its stack slots. Value is one as it is, and with the
+//nan_boxing feature NanBox is used instead. Packing gets the heap since
+//some ints are too big for a NanBox and have to be boxed
+pub trait StackSlot: Copy {
+    //whether pack can allocate
+    const ALLOCATES: bool;
+
+    fn pack(val: Value, heap: &mut Heap) -> Self;
+    fn unpack(self) -> Value;
+    //marks whatever the slot keeps alive, including any box of its own
+    fn trace(&self, tracer: &mut Tracer);
+}
+
+impl StackSlot for Value {
+    const ALLOCATES: bool = false;
+
+    #[inline]
+    fn pack(val: Value, _heap: &mut Heap) -> Self {
+        val
+    }
+
+    #[inline]
+    fn unpack(self) -> Value {
+        self
+    }
+
+    fn trace(&self, tracer: &mut Tracer) {
+        tracer.mark_value(self);
+    }
+}
+
+//A Value in 8 bytes instead of 16. Any float that isn't NaN is stored as
+//is, and everything else hides in the payload of a quiet NaN:
+//
+//    s 1111111111111 tt pppp...pppp
+//    | |             |  |
+//    | quiet NaN     |  48 bit payload
+//    |               |
+//    +----- tag -----+
+//
+//Real NaNs all get turned into the one the hardware makes, which doesn't
+//have every quiet bit set, so they can't be mistaken for anything else.
+//Objects are pointers in the payload, with their kind in the bottom three
+//bits that alignment leaves free. Addresses have to fit in 48 bits, which
+//they do in user space on x86_64 and aarch64
+#[derive(Clone, Copy)]
+pub struct NanBox(u64);
+
+const QNAN: u64 = 0x7ffc_0000_0000_0000;
+const SIGN: u64 = 1 << 63;
+const PAYLOAD: u64 = (1 << 48) - 1;
+
+const TAG_MASK: u64 = SIGN | 3 << 48;
+const TAG_SINGLETON: u64 = 0;
+const TAG_INT: u64 = 1 << 48;
+const TAG_OBJECT: u64 = 2 << 48;
+//ints that don't fit in the payload point at one of these instead
+const TAG_BIG_INT: u64 = 3 << 48;
+
+const NIL: u64 = QNAN | TAG_SINGLETON | 1;
+const FALSE: u64 = QNAN | TAG_SINGLETON | 2;
+const TRUE: u64 = QNAN | TAG_SINGLETON | 3;
+
+const KIND_MASK: u64 = 0b111;
+const KIND_STR: u64 = 0;
+const KIND_FUNCTION: u64 = 1;
+const KIND_CLOSURE: u64 = 2;
+const KIND_CLASS: u64 = 3;
+const KIND_INSTANCE: u64 = 4;
+const KIND_BOUND_METHOD: u64 = 5;
+const KIND_NATIVE: u64 = 6;
+const KIND_LIST: u64 = 7;
+
+//the range of ints that fit in the payload
+const INT_MIN: i64 = -(1 << 47);
+const INT_MAX: i64 = (1 << 47) - 1;
+
+//an int on the heap, only NanBox makes these
+pub struct BigInt(i64);
+
+impl Trace for BigInt {
+    fn trace(&self, _tracer: &mut Tracer) {}
+}
+
+impl NanBox {
+    fn object<T>(gc: Gc<T>, kind: u64) -> Self {
+        let addr = gc.as_raw() as u64;
+        debug_assert!(addr & !PAYLOAD == 0, "object address doesn't fit in 48 bits");
+        debug_assert!(addr & KIND_MASK == 0, "object isn't 8 byte aligned");
+        NanBox(QNAN | TAG_OBJECT | addr | kind)
+    }
+
+    //T has to be whatever was packed, which the kind or tag says
+    unsafe fn gc<T>(self) -> Gc<T> {
+        Gc::from_raw((self.0 & PAYLOAD & !KIND_MASK) as *const ())
+    }
+
+    fn is_float(self) -> bool {
+        self.0 & QNAN != QNAN
+    }
+}
+
+impl StackSlot for NanBox {
+    const ALLOCATES: bool = true;
+
+    #[inline]
+    fn pack(val: Value, heap: &mut Heap) -> Self {
+        match val {
+            Value::Number(n) if n.is_nan() => NanBox(f64::NAN.to_bits()),
+            Value::Number(n) => NanBox(n.to_bits()),
+            Value::Int(n) if (INT_MIN..=INT_MAX).contains(&n) => {
+                NanBox(QNAN | TAG_INT | (n as u64 & PAYLOAD))
+            }
+            //the heap never collects by itself, so this is safe anywhere.
+            //The vm collects at its next safepoint instead
+            Value::Int(n) => {
+                let boxed = heap.alloc(BigInt(n));
+                NanBox(QNAN | TAG_BIG_INT | boxed.as_raw() as u64)
+            }
+            Value::Nil => NanBox(NIL),
+            Value::Bool(false) => NanBox(FALSE),
+            Value::Bool(true) => NanBox(TRUE),
+            Value::Str(obj) => NanBox::object(obj, KIND_STR),
+            Value::Function(obj) => NanBox::object(obj, KIND_FUNCTION),
+            Value::Closure(obj) => NanBox::object(obj, KIND_CLOSURE),
+            Value::Class(obj) => NanBox::object(obj, KIND_CLASS),
+            Value::Instance(obj) => NanBox::object(obj, KIND_INSTANCE),
+            Value::BoundMethod(obj) => NanBox::object(obj, KIND_BOUND_METHOD),
+            Value::Native(obj) => NanBox::object(obj, KIND_NATIVE),
+            Value::List(obj) => NanBox::object(obj, KIND_LIST),
+        }
+    }
+
+    #[inline]
+    fn unpack(self) -> Value {
+        if self.is_float() {
+            return Value::Number(f64::from_bits(self.0));
+        }
+
+        //the handles were live when they were packed, and whatever's
+        //holding this slot has been keeping them alive since
+        unsafe {
+            match self.0 & TAG_MASK {
+                //shifted up and back down to sign extend it
+                TAG_INT => Value::Int(((self.0 & PAYLOAD) << 16) as i64 >> 16),
+                TAG_BIG_INT => Value::Int(self.gc::<BigInt>().0),
+                TAG_OBJECT => match self.0 & KIND_MASK {
+                    KIND_STR => Value::Str(self.gc()),
+                    KIND_FUNCTION => Value::Function(self.gc()),
+                    KIND_CLOSURE => Value::Closure(self.gc()),
+                    KIND_CLASS => Value::Class(self.gc()),
+                    KIND_INSTANCE => Value::Instance(self.gc()),
+                    KIND_BOUND_METHOD => Value::BoundMethod(self.gc()),
+                    KIND_NATIVE => Value::Native(self.gc()),
+                    _ => Value::List(self.gc()),
+                },
+                _ => match self.0 {
+                    NIL => Value::Nil,
+                    FALSE => Value::Bool(false),
+                    TRUE => Value::Bool(true),
+                    _ => unreachable!("bad NanBox {:#x}", self.0),
+                },
+            }
+        }
+    }
+
+    fn trace(&self, tracer: &mut Tracer) {
+        if !self.is_float() && self.0 & TAG_MASK == TAG_BIG_INT {
+            tracer.mark(unsafe { self.gc::<BigInt>() });
+        } else {
+            tracer.mark_value(&self.unpack());
+        }
+    }
+}
@@ -0,0 +1,262 @@
+use crate::object::List;
+use crate::vm::{Value, Vm};
+use std::io::{self, BufRead};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+//The standard library, every Vm starts out with these as globals.
+//Natives check their own argument types, the vm only checks arity
+pub fn define_stdlib(vm: &mut Vm) {
+    vm.define_native("clock", 0, clock);
+    vm.define_native("input", 0, input);
+
+    vm.define_native("str", 1, str);
+    vm.define_native("num", 1, num);
+    vm.define_native("len", 1, len);
+    vm.define_native("type", 1, type_of);
+
+    vm.define_native("sqrt", 1, sqrt);
+    vm.define_native("floor", 1, floor);
+    vm.define_native("pow", 2, pow);
+    vm.define_native("random", 0, random);
+    vm.define_native("seed", 1, seed);
+
+    vm.define_native("substr", 3, substr);
+    vm.define_native("index_of", 2, index_of);
+    vm.define_native("upper", 1, upper);
+    vm.define_native("split", 2, split);
+    vm.define_native("get", 2, get);
+}
+
+pub fn time_seed() -> u64 {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_nanos() as u64);
+    mix_seed(nanos)
+}
+
+//either kind of number, as a float
+fn number_arg(args: &[Value], idx: usize, native: &str) -> Result<f64, String> {
+    args[idx]
+        .as_f64()
+        .ok_or_else(|| format!("{native}() expects a number"))
+}
+
+fn string_arg<'a>(args: &'a [Value], idx: usize, native: &str) -> Result<&'a str, String> {
+    match &args[idx] {
+        Value::Str(string) => Ok(&string.chars),
+        _ => Err(format!("{native}() expects a string")),
+    }
+}
+
+//a number that can be used to index something, whole floats count too
+//as long as they fit in a usize without the cast saturating
+fn index_arg(args: &[Value], idx: usize, native: &str) -> Result<usize, String> {
+    let index = match args[idx] {
+        Value::Int(n) => usize::try_from(n).ok(),
+        //usize::MAX rounds up to 2^64 as a float, which is just out of range
+        Value::Number(n) if n.fract() == 0.0 && (0.0..usize::MAX as f64).contains(&n) => {
+            Some(n as usize)
+        }
+        Value::Number(_) => None,
+        _ => return Err(format!("{native}() expects a number")),
+    };
+    index.ok_or_else(|| format!("{native}() expects a whole, non-negative index"))
+}
+
+//seconds since the epoch, only meant for timing things
+fn clock(_vm: &mut Vm, _args: &[Value]) -> Result<Value, String> {
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|err| err.to_string())?;
+    Ok(Value::Number(time.as_secs_f64()))
+}
+
+//a line of input without its newline, nil once there's nothing left
+fn input(vm: &mut Vm, _args: &[Value]) -> Result<Value, String> {
+    let mut line = String::new();
+    let read = match &mut vm.input {
+        Some(input) => input.read_line(&mut line),
+        None => io::stdin().read_line(&mut line),
+    };
+
+    match read {
+        Ok(0) => Ok(Value::Nil),
+        Ok(_) => {
+            let len = line.trim_end_matches(['\n', '\r']).len();
+            line.truncate(len);
+            Ok(Value::Str(vm.intern_owned(line)))
+        }
+        Err(err) => Err(format!("input() failed: {err}")),
+    }
+}
+
+fn str(vm: &mut Vm, args: &[Value]) -> Result<Value, String> {
+    match args[0] {
+        Value::Str(_) => Ok(args[0]),
+        other => Ok(Value::Str(vm.intern_owned(other.to_string()))),
+    }
+}
+
+//nil if the string isn't a number, so input can be checked.
+//Whole numbers come back as ints, like they would from a literal
+fn num(_vm: &mut Vm, args: &[Value]) -> Result<Value, String> {
+    match &args[0] {
+        Value::Number(_) | Value::Int(_) => Ok(args[0]),
+        Value::Str(string) => {
+            let chars = string.chars.trim();
+            Ok(match chars.parse() {
+                Ok(n) => Value::Int(n),
+                Err(_) => chars.parse().map_or(Value::Nil, Value::Number),
+            })
+        }
+        _ => Err("num() expects a string or a number".to_owned()),
+    }
+}
+
+fn len(_vm: &mut Vm, args: &[Value]) -> Result<Value, String> {
+    match &args[0] {
+        Value::Str(string) => Ok(Value::Int(string.chars.chars().count() as i64)),
+        Value::List(list) => Ok(Value::Int(list.items.len() as i64)),
+        _ => Err("len() expects a string or a list".to_owned()),
+    }
+}
+
+fn type_of(vm: &mut Vm, args: &[Value]) -> Result<Value, String> {
+    let name = match args[0] {
+        Value::Nil => "nil",
+        Value::Bool(_) => "bool",
+        Value::Number(_) | Value::Int(_) => "number",
+        Value::Str(_) => "string",
+        Value::Function(_) | Value::Closure(_) | Value::BoundMethod(_) | Value::Native(_) => {
+            "function"
+        }
+        Value::Class(_) => "class",
+        Value::Instance(_) => "instance",
+        Value::List(_) => "list",
+    };
+    Ok(Value::Str(vm.intern(name)))
+}
+
+fn sqrt(_vm: &mut Vm, args: &[Value]) -> Result<Value, String> {
+    Ok(Value::Number(number_arg(args, 0, "sqrt")?.sqrt()))
+}
+
+//ints are already whole, so they come back as they are
+fn floor(_vm: &mut Vm, args: &[Value]) -> Result<Value, String> {
+    match args[0] {
+        Value::Int(_) => Ok(args[0]),
+        _ => Ok(Value::Number(number_arg(args, 0, "floor")?.floor())),
+    }
+}
+
+//an int if both are and the result fits, same as the arithmetic ops
+fn pow(_vm: &mut Vm, args: &[Value]) -> Result<Value, String> {
+    if let (Value::Int(base), Value::Int(exp)) = (args[0], args[1]) {
+        let exact = u32::try_from(exp).ok().and_then(|exp| base.checked_pow(exp));
+        if let Some(n) = exact {
+            return Ok(Value::Int(n));
+        }
+    }
+
+    let base = number_arg(args, 0, "pow")?;
+    let exp = number_arg(args, 1, "pow")?;
+    Ok(Value::Number(base.powf(exp)))
+}
+
+//xorshift64*, a number in [0, 1)
+fn random(vm: &mut Vm, _args: &[Value]) -> Result<Value, String> {
+    let mut x = vm.rng_state;
+    x ^= x >> 12;
+    x ^= x << 25;
+    x ^= x >> 27;
+    vm.rng_state = x;
+
+    let bits = x.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 11;
+    Ok(Value::Number(bits as f64 / (1u64 << 53) as f64))
+}
+
+//same seed, same sequence out of random
+fn seed(vm: &mut Vm, args: &[Value]) -> Result<Value, String> {
+    let seed = match args[0] {
+        Value::Int(n) => n as u64,
+        _ => number_arg(args, 0, "seed")?.to_bits(),
+    };
+    vm.rng_state = mix_seed(seed);
+    Ok(Value::Nil)
+}
+
+//splitmix64's finalizer, so nearby seeds don't start out nearby
+fn mix_seed(seed: u64) -> u64 {
+    let mut z = seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^= z >> 31;
+    //xorshift gets stuck on zero
+    if z == 0 {
+        0x9E37_79B9_7F4A_7C15
+    } else {
+        z
+    }
+}
+
+//substr(s, start, len), counted in chars
+fn substr(vm: &mut Vm, args: &[Value]) -> Result<Value, String> {
+    let string = string_arg(args, 0, "substr")?;
+    let start = index_arg(args, 1, "substr")?;
+    let len = index_arg(args, 2, "substr")?;
+
+    let char_count = string.chars().count();
+    if start.checked_add(len).is_none_or(|end| end > char_count) {
+        return Err(format!(
+            "substr() range of {len} from {start} is out of bounds for a string of length {char_count}"
+        ));
+    }
+
+    let sub: String = string.chars().skip(start).take(len).collect();
+    Ok(Value::Str(vm.intern_owned(sub)))
+}
+
+//char index of the first match, or -1
+fn index_of(_vm: &mut Vm, args: &[Value]) -> Result<Value, String> {
+    let haystack = string_arg(args, 0, "index_of")?;
+    let needle = string_arg(args, 1, "index_of")?;
+
+    let idx = haystack
+        .find(needle)
+        .map_or(-1, |byte_idx| haystack[..byte_idx].chars().count() as i64);
+    Ok(Value::Int(idx))
+}
+
+fn upper(vm: &mut Vm, args: &[Value]) -> Result<Value, String> {
+    let upper = string_arg(args, 0, "upper")?.to_uppercase();
+    Ok(Value::Str(vm.intern_owned(upper)))
+}
+
+fn split(vm: &mut Vm, args: &[Value]) -> Result<Value, String> {
+    let string = string_arg(args, 0, "split")?;
+    let sep = string_arg(args, 1, "split")?;
+    if sep.is_empty() {
+        return Err("split() separator can't be empty".to_owned());
+    }
+
+    //each piece sits on the stack until the list owns it
+    let base = vm.stack.len();
+    for piece in string.split(sep) {
+        let piece = vm.intern(piece);
+        vm.push(Value::Str(piece));
+    }
+
+    let items = vm.pop_from(base);
+    Ok(Value::List(vm.alloc(List { items })))
+}
+
+fn get(_vm: &mut Vm, args: &[Value]) -> Result<Value, String> {
+    let Value::List(list) = args[0] else {
+        return Err("get() expects a list".to_owned());
+    };
+    let idx = index_arg(args, 1, "get")?;
+
+    list.items.get(idx).copied().ok_or_else(|| {
+        format!("get() index {idx} is out of bounds for a list of length {}", list.items.len())
+    })
+}
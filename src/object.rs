@@ -0,0 +1,232 @@
+use crate::heap::{Gc, Trace, Tracer};
+use crate::table::Table;
+use crate::interpreter::OwnedValue;
+use crate::vm::{Chunk, Value, Vm};
+use std::cell::RefCell;
+use std::fmt;
+
+//Everything in here lives on the Heap and is
+//handed around by Values as a Gc handle
+
+//Strings are interned by the vm, so there's only ever one
+//object per distinct string and handles can be compared directly
+#[derive(Debug)]
+pub struct ObjString {
+    pub chars: String,
+    pub hash: u32,
+}
+
+impl ObjString {
+    pub fn new(chars: String) -> Self {
+        let hash = hash_string(&chars);
+        Self { chars, hash }
+    }
+}
+
+//FNV-1a
+pub fn hash_string(chars: &str) -> u32 {
+    let mut hash = 2166136261u32;
+    for byte in chars.bytes() {
+        hash ^= byte as u32;
+        hash = hash.wrapping_mul(16777619);
+    }
+    hash
+}
+
+//name is None for the top level script
+#[derive(Debug, Default)]
+pub struct Function {
+    pub arity: u8,
+    pub upvalue_count: usize,
+    pub chunk: Chunk,
+    pub name: Option<Gc<ObjString>>,
+}
+
+//functions only exist at runtime wrapped in one of these
+#[derive(Debug)]
+pub struct Closure {
+    pub function: Gc<Function>,
+    pub upvalues: Vec<Gc<RefCell<Upvalue>>>,
+}
+
+//an upvalue points at a stack slot for as long as the variable
+//lives there, then takes ownership of the value once it's popped
+#[derive(Debug)]
+pub enum Upvalue {
+    Open(usize),
+    Closed(Value),
+}
+
+#[derive(Debug)]
+pub struct Class {
+    pub name: Gc<ObjString>,
+    pub methods: Table<Gc<Closure>>,
+}
+
+#[derive(Debug)]
+pub struct Instance {
+    pub class: Gc<RefCell<Class>>,
+    pub fields: Table<Value>,
+}
+
+//a method pulled off an instance, remembers which instance it came from
+#[derive(Debug)]
+pub struct BoundMethod {
+    pub receiver: Value,
+    pub method: Gc<Closure>,
+}
+
+//args are copies of what's on the stack, which still holds them so
+//they stay alive for the whole call. Errors become runtime errors
+pub type NativeFn = fn(&mut Vm, &[Value]) -> Result<Value, String>;
+
+//What natives defined by an embedder get instead. They never see the vm
+//or a Gc handle, so there's nothing for them to hold onto past a collection
+pub type HostFn = fn(&[OwnedValue]) -> Result<OwnedValue, String>;
+
+pub enum NativeFunction {
+    Vm(NativeFn),
+    Host(HostFn),
+}
+
+pub struct Native {
+    pub name: Gc<ObjString>,
+    pub arity: u8,
+    pub function: NativeFunction,
+}
+
+//natives are the only thing that make these for now
+#[derive(Debug)]
+pub struct List {
+    pub items: Vec<Value>,
+}
+
+impl Trace for ObjString {
+    fn trace(&self, _tracer: &mut Tracer) {}
+
+    fn extra_size(&self) -> usize {
+        self.chars.capacity()
+    }
+}
+
+impl Trace for Function {
+    fn trace(&self, tracer: &mut Tracer) {
+        if let Some(name) = self.name {
+            tracer.mark(name);
+        }
+        for val in &self.chunk.const_pool {
+            tracer.mark_value(val);
+        }
+    }
+
+    fn extra_size(&self) -> usize {
+        self.chunk.bytecode.capacity()
+    }
+}
+
+impl Trace for Closure {
+    fn trace(&self, tracer: &mut Tracer) {
+        tracer.mark(self.function);
+        for upvalue in &self.upvalues {
+            tracer.mark(*upvalue);
+        }
+    }
+}
+
+impl Trace for Upvalue {
+    //an open upvalue's value is on the stack, which is already a root
+    fn trace(&self, tracer: &mut Tracer) {
+        if let Upvalue::Closed(val) = self {
+            tracer.mark_value(val);
+        }
+    }
+}
+
+impl Trace for Class {
+    fn trace(&self, tracer: &mut Tracer) {
+        tracer.mark(self.name);
+        for (name, method) in self.methods.iter() {
+            tracer.mark(name);
+            tracer.mark(method);
+        }
+    }
+}
+
+impl Trace for Instance {
+    fn trace(&self, tracer: &mut Tracer) {
+        tracer.mark(self.class);
+        for (name, val) in self.fields.iter() {
+            tracer.mark(name);
+            tracer.mark_value(&val);
+        }
+    }
+}
+
+impl Trace for BoundMethod {
+    fn trace(&self, tracer: &mut Tracer) {
+        tracer.mark_value(&self.receiver);
+        tracer.mark(self.method);
+    }
+}
+
+impl Trace for Native {
+    fn trace(&self, tracer: &mut Tracer) {
+        tracer.mark(self.name);
+    }
+}
+
+impl Trace for List {
+    fn trace(&self, tracer: &mut Tracer) {
+        for item in &self.items {
+            tracer.mark_value(item);
+        }
+    }
+
+    fn extra_size(&self) -> usize {
+        self.items.capacity() * std::mem::size_of::<Value>()
+    }
+}
+
+//the fn pointer isn't worth printing
+impl fmt::Debug for Native {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Native")
+            .field("name", &self.name)
+            .field("arity", &self.arity)
+            .finish()
+    }
+}
+
+impl fmt::Display for ObjString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.chars)
+    }
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "<fn {name}>"),
+            None => write!(f, "<script>"),
+        }
+    }
+}
+
+impl fmt::Display for Native {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<native fn {}>", self.name)
+    }
+}
+
+impl fmt::Display for List {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[")?;
+        for (i, item) in self.items.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{item}")?;
+        }
+        write!(f, "]")
+    }
+}
@@ -0,0 +1,145 @@
+use kara::diagnostic::{self, Diagnostic};
+use kara::{CompileErrorKind, Error, Interpreter, Scanner, TokenType};
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+use std::env;
+use std::path::PathBuf;
+
+//Everything typed goes through the same Interpreter, so globals
+//defined on one line are still there on the next
+pub fn run() {
+    let mut editor = match DefaultEditor::new() {
+        Ok(editor) => editor,
+        Err(err) => {
+            eprintln!("Error: unable to start the repl: {err}");
+            std::process::exit(74);
+        }
+    };
+
+    let history = history_path();
+    if let Some(path) = &history {
+        //there won't be one the first time round
+        _ = editor.load_history(path);
+    }
+
+    let mut interpreter = Interpreter::new();
+    interpreter.set_echo_expressions(true);
+    let mut buffer = String::new();
+
+    loop {
+        let prompt = if buffer.is_empty() { "> " } else { ". " };
+
+        match editor.readline(prompt) {
+            Ok(line) => {
+                let blank = line.trim().is_empty();
+                if blank && buffer.is_empty() {
+                    continue;
+                }
+
+                buffer.push_str(&line);
+                buffer.push('\n');
+
+                //a blank line pushes through whatever's there,
+                //so a missing ';' doesn't leave you stuck
+                if !blank && is_incomplete(&buffer) {
+                    continue;
+                }
+
+                _ = editor.add_history_entry(buffer.trim_end());
+                eval(&mut interpreter, &buffer);
+                buffer.clear();
+            }
+
+            //ctrl-c throws away the current input, ctrl-d quits
+            Err(ReadlineError::Interrupted) => buffer.clear(),
+            Err(ReadlineError::Eof) => break,
+            Err(err) => {
+                eprintln!("Error: {err}");
+                break;
+            }
+        }
+    }
+
+    if let Some(path) = &history {
+        _ = editor.save_history(path);
+    }
+}
+
+fn eval(interpreter: &mut Interpreter, source: &str) {
+    //the vm resets itself after a runtime error, so carrying on is fine
+    match interpreter.eval(source) {
+        Ok(()) => {}
+        Err(Error::Compile(errors)) => {
+            diagnostic::eprint_all(source, errors.iter().map(Diagnostic::from));
+        }
+        Err(err) => eprintln!("{err}"),
+    }
+}
+
+//more input is needed if there's an open brace, paren or string,
+//or the last thing typed doesn't finish a statement
+fn is_incomplete(source: &str) -> bool {
+    let mut scanner = Scanner::new(source);
+    let mut depth = 0i32;
+    let mut last = TokenType::Eof;
+
+    for token in scanner.by_ref() {
+        match token.kind {
+            TokenType::LeftBrace | TokenType::LeftParen => depth += 1,
+            TokenType::RightBrace | TokenType::RightParen => depth -= 1,
+            TokenType::Eof => break,
+            _ => {}
+        }
+        last = token.kind;
+    }
+
+    let unterminated = scanner
+        .take_errors()
+        .iter()
+        .any(|err| err.kind == CompileErrorKind::UnterminatedString);
+
+    //last is only still Eof if there were no tokens at all, like a line
+    //that's just a comment, and there's nothing to wait for then
+    let finished = matches!(last, TokenType::Semicolon | TokenType::RightBrace | TokenType::Eof);
+    unterminated || depth > 0 || !finished
+}
+
+fn history_path() -> Option<PathBuf> {
+    env::var_os("HOME").map(|home| PathBuf::from(home).join(".kara_history"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::is_incomplete;
+
+    #[test]
+    fn waits_for_open_braces_and_parens() {
+        assert!(is_incomplete("fun f() {"));
+        assert!(is_incomplete("if (x > 1) {\n  print x;"));
+        assert!(is_incomplete("print (1 +"));
+        assert!(!is_incomplete("fun f() {\n  print 1;\n}"));
+    }
+
+    #[test]
+    fn waits_for_unterminated_strings() {
+        assert!(is_incomplete("print \"hello"));
+        assert!(is_incomplete("print \"a;\nb"));
+        assert!(!is_incomplete("print \"a;\nb\";"));
+    }
+
+    #[test]
+    fn waits_for_a_missing_semicolon() {
+        assert!(is_incomplete("print 1"));
+        assert!(is_incomplete("var x = 1 +"));
+        assert!(!is_incomplete("print 1;"));
+    }
+
+    #[test]
+    fn comments_and_blank_lines_are_complete() {
+        assert!(!is_incomplete("// just a comment"));
+        assert!(!is_incomplete("  \n"));
+        assert!(!is_incomplete(""));
+        //a comment after something unfinished doesn't finish it
+        assert!(is_incomplete("print 1 // no semicolon"));
+    }
+}
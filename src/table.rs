@@ -0,0 +1,314 @@
+use crate::heap::Gc;
+use crate::object::ObjString;
+
+//grow once the table is this full, tombstones included
+const MAX_LOAD: f64 = 0.75;
+const MIN_CAPACITY: usize = 8;
+
+//Keys are interned, so comparing them is just a pointer compare
+//and their hash is already cached on the string itself
+#[derive(Debug, Clone, Copy)]
+enum Entry<V> {
+    Empty,
+    //left behind by delete so probe sequences don't get cut short
+    Tombstone,
+    Full(Gc<ObjString>, V),
+}
+
+//open addressing with linear probing, capacity is always a power of two
+#[derive(Debug, Clone)]
+pub struct Table<V> {
+    entries: Vec<Entry<V>>,
+    //full entries plus tombstones, which is what the load factor cares about
+    used: usize,
+    len: usize,
+}
+
+impl<V: Copy> Table<V> {
+    pub fn new() -> Self {
+        Self {
+            entries: Vec::new(),
+            used: 0,
+            len: 0,
+        }
+    }
+
+    //only the tests look at this
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn get(&self, key: Gc<ObjString>) -> Option<V> {
+        if self.len == 0 {
+            return None;
+        }
+
+        match self.entries[self.find_entry(key)] {
+            Entry::Full(_, val) => Some(val),
+            _ => None,
+        }
+    }
+
+    //returns true if the key wasn't already in the table
+    pub fn set(&mut self, key: Gc<ObjString>, val: V) -> bool {
+        if (self.used + 1) as f64 > self.capacity() as f64 * MAX_LOAD {
+            self.grow();
+        }
+
+        let idx = self.find_entry(key);
+        let is_new = !matches!(self.entries[idx], Entry::Full(..));
+        if is_new {
+            self.len += 1;
+            //reusing a tombstone doesn't change how full the table is
+            if matches!(self.entries[idx], Entry::Empty) {
+                self.used += 1;
+            }
+        }
+
+        self.entries[idx] = Entry::Full(key, val);
+        is_new
+    }
+
+    pub fn delete(&mut self, key: Gc<ObjString>) -> bool {
+        if self.len == 0 {
+            return false;
+        }
+
+        let idx = self.find_entry(key);
+        if !matches!(self.entries[idx], Entry::Full(..)) {
+            return false;
+        }
+
+        self.entries[idx] = Entry::Tombstone;
+        self.len -= 1;
+        true
+    }
+
+    pub fn add_all(&mut self, from: &Table<V>) {
+        for (key, val) in from.iter() {
+            self.set(key, val);
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (Gc<ObjString>, V)> + '_ {
+        self.entries.iter().filter_map(|entry| match *entry {
+            Entry::Full(key, val) => Some((key, val)),
+            _ => None,
+        })
+    }
+
+    //only deletes, never grows, so it's fine to call mid collection
+    pub fn retain(&mut self, mut keep: impl FnMut(Gc<ObjString>, V) -> bool) {
+        for entry in self.entries.iter_mut() {
+            if let Entry::Full(key, val) = *entry {
+                if !keep(key, val) {
+                    *entry = Entry::Tombstone;
+                    self.len -= 1;
+                }
+            }
+        }
+    }
+
+    //the one lookup that compares contents, used by the interner
+    //to find out whether a string already has an object
+    pub fn find_string(&self, chars: &str, hash: u32) -> Option<Gc<ObjString>> {
+        if self.len == 0 {
+            return None;
+        }
+
+        let mut idx = hash as usize & (self.capacity() - 1);
+        loop {
+            match self.entries[idx] {
+                Entry::Empty => return None,
+                Entry::Full(key, _) if key.hash == hash && key.chars == chars => {
+                    return Some(key);
+                }
+                _ => {}
+            }
+
+            idx = (idx + 1) & (self.capacity() - 1);
+        }
+    }
+
+    //index of the key's entry, or of wherever it should go if it's missing
+    fn find_entry(&self, key: Gc<ObjString>) -> usize {
+        let mut idx = key.hash as usize & (self.capacity() - 1);
+        let mut tombstone = None;
+
+        loop {
+            match self.entries[idx] {
+                Entry::Empty => return tombstone.unwrap_or(idx),
+                Entry::Tombstone => {
+                    tombstone.get_or_insert(idx);
+                }
+                Entry::Full(existing, _) if Gc::ptr_eq(existing, key) => return idx,
+                Entry::Full(..) => {}
+            }
+
+            idx = (idx + 1) & (self.capacity() - 1);
+        }
+    }
+
+    //tombstones are dropped on the way over
+    fn grow(&mut self) {
+        let capacity = (self.capacity() * 2).max(MIN_CAPACITY);
+        let old = std::mem::replace(&mut self.entries, vec![Entry::Empty; capacity]);
+
+        self.used = 0;
+        self.len = 0;
+        for entry in old {
+            if let Entry::Full(key, val) = entry {
+                let idx = self.find_entry(key);
+                self.entries[idx] = Entry::Full(key, val);
+                self.used += 1;
+                self.len += 1;
+            }
+        }
+    }
+}
+
+impl<V: Copy> Default for Table<V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::heap::Heap;
+
+    //all the same hash, so they share one probe sequence
+    fn colliding(heap: &mut Heap, names: &[&str]) -> Vec<Gc<ObjString>> {
+        names
+            .iter()
+            .map(|name| {
+                heap.alloc(ObjString {
+                    chars: name.to_string(),
+                    hash: 7,
+                })
+            })
+            .collect()
+    }
+
+    #[test]
+    fn set_get_delete() {
+        let mut heap = Heap::new();
+        let [a, b] = colliding(&mut heap, &["a", "b"])[..] else {
+            unreachable!()
+        };
+        let mut table = Table::new();
+
+        assert_eq!(table.get(a), None);
+        assert!(!table.delete(a));
+        assert!(table.set(a, 1));
+        assert!(!table.set(a, 2));
+        assert!(table.set(b, 3));
+        assert_eq!((table.get(a), table.get(b), table.len()), (Some(2), Some(3), 2));
+
+        assert!(table.delete(a));
+        assert!(!table.delete(a));
+        assert_eq!((table.get(a), table.get(b), table.len()), (None, Some(3), 1));
+    }
+
+    #[test]
+    fn tombstones_keep_probe_sequences_going() {
+        let mut heap = Heap::new();
+        let keys = colliding(&mut heap, &["a", "b", "c"]);
+        let mut table = Table::new();
+        for (i, &key) in keys.iter().enumerate() {
+            table.set(key, i);
+        }
+
+        //c is only reachable by probing past b's slot
+        table.delete(keys[1]);
+        assert_eq!(table.get(keys[2]), Some(2));
+        assert!(table.find_string("c", 7).is_some_and(|key| Gc::ptr_eq(key, keys[2])));
+        assert!(table.find_string("b", 7).is_none());
+
+        //b goes back in the tombstone it left, which doesn't use up any more room
+        let used = table.used;
+        assert!(table.set(keys[1], 10));
+        assert_eq!(table.used, used);
+        assert_eq!(table.get(keys[1]), Some(10));
+        assert_eq!(table.len(), 3);
+
+        //setting c again finds the existing entry past the reused slot
+        assert!(!table.set(keys[2], 20));
+        assert_eq!(table.len(), 3);
+        assert_eq!(table.iter().count(), 3);
+    }
+
+    #[test]
+    fn retain_leaves_tombstones() {
+        let mut heap = Heap::new();
+        let keys = colliding(&mut heap, &["a", "b", "c", "d"]);
+        let mut table = Table::new();
+        for (i, &key) in keys.iter().enumerate() {
+            table.set(key, i);
+        }
+
+        table.retain(|_, val| val % 2 == 1);
+        assert_eq!(table.len(), 2);
+        assert_eq!(table.used, 4);
+        assert_eq!(table.get(keys[0]), None);
+        assert_eq!(table.get(keys[1]), Some(1));
+        assert_eq!(table.get(keys[3]), Some(3));
+    }
+
+    #[test]
+    fn grows_past_the_load_factor() {
+        let mut heap = Heap::new();
+        let keys: Vec<_> = (0..100)
+            .map(|i| heap.alloc(ObjString::new(format!("key{i}"))))
+            .collect();
+        let mut table = Table::new();
+
+        //6 entries is exactly 0.75 of the minimum capacity, the 7th grows it
+        for (i, &key) in keys[..6].iter().enumerate() {
+            table.set(key, i);
+        }
+        assert_eq!(table.capacity(), MIN_CAPACITY);
+        table.set(keys[6], 6);
+        assert_eq!(table.capacity(), MIN_CAPACITY * 2);
+
+        for (i, &key) in keys.iter().enumerate() {
+            table.set(key, i);
+        }
+        assert_eq!(table.len(), 100);
+        assert!(table.len() as f64 <= table.capacity() as f64 * MAX_LOAD);
+        for (i, &key) in keys.iter().enumerate() {
+            assert_eq!(table.get(key), Some(i));
+        }
+    }
+
+    #[test]
+    fn grow_drops_tombstones() {
+        let mut heap = Heap::new();
+        let keys: Vec<_> = (0..7)
+            .map(|i| heap.alloc(ObjString::new(format!("key{i}"))))
+            .collect();
+        let mut table = Table::new();
+
+        for &key in &keys[..6] {
+            table.set(key, 0);
+        }
+        for &key in &keys[..5] {
+            table.delete(key);
+        }
+        //one live entry but six used slots, so the next new key grows the table
+        assert_eq!((table.len(), table.used), (1, 6));
+        table.set(keys[6], 1);
+
+        assert_eq!(table.capacity(), MIN_CAPACITY * 2);
+        assert_eq!((table.len(), table.used), (2, 2));
+        assert_eq!(table.get(keys[5]), Some(0));
+        assert_eq!(table.get(keys[6]), Some(1));
+        assert_eq!(table.get(keys[0]), None);
+    }
+}
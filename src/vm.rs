@@ -1,53 +1,191 @@
+use crate::compile::CompileError;
+use crate::heap::{Gc, Heap, Trace, Tracer};
+use crate::interpreter::{self, OwnedValue};
+#[cfg(feature = "nan_boxing")]
+use crate::nanbox::NanBox;
+use crate::nanbox::StackSlot;
+use crate::native;
+use crate::object::{
+    hash_string, BoundMethod, Class, Closure, Function, Instance, List, Native, NativeFn,
+    NativeFunction, ObjString, Upvalue,
+};
+use crate::table::Table;
 use arrayvec::ArrayVec;
+use std::cell::RefCell;
+use std::cmp::Ordering;
 use std::fmt;
+use std::io::{self, BufRead, Write};
+use std::mem;
 use strum_macros::FromRepr;
 use Op::*;
-use Value::*;
+use Value::{Bool, Int, Nil, Number, Str};
 
-macro_rules! binary_op {
-    ($stack:expr, $op:tt, $return_type:ident) => {{
-        let b = match $stack.pop().unwrap() {
-            Value::Number(val) => val,
-            _ => panic!("Invalid operands"),
+//comparisons are exact even between ints and floats, and
+//all of them are false against NaN like IEEE 754 says
+macro_rules! comparison_op {
+    ($vm:expr, $op:tt) => {{
+        let ord = match ($vm.peek(1), $vm.peek(0)) {
+            (Int(a), Int(b)) => Some(a.cmp(&b)),
+            (Number(a), Number(b)) => a.partial_cmp(&b),
+            (Int(a), Number(b)) => cmp_int_float(a, b),
+            (Number(a), Int(b)) => cmp_int_float(b, a).map(Ordering::reverse),
+            _ => return Err($vm.runtime_error("Operands must be numbers")),
         };
-        let a = match $stack.pop().unwrap() {
-            Value::Number(val) => val,
-            _ => panic!("Invalid operands"),
+        $vm.stack.pop();
+        $vm.stack.pop();
+        $vm.push(Bool(ord.is_some_and(|ord| ord $op Ordering::Equal)));
+    }};
+}
+
+//ints only stay ints when both sides are, anything with a float in
+//it gets done as floats. Ints that overflow get redone as floats
+//instead of wrapping
+macro_rules! arithmetic_op {
+    ($vm:expr, $op:tt, $checked:ident) => {{
+        let result = match ($vm.peek(1), $vm.peek(0)) {
+            (Int(a), Int(b)) => a.$checked(b).map_or(Number(a as f64 $op b as f64), Int),
+            (a, b) => {
+                let (Some(a), Some(b)) = (a.as_f64(), b.as_f64()) else {
+                    return Err($vm.runtime_error("Operands must be numbers"));
+                };
+                Number(a $op b)
+            }
         };
-        $stack.push(Value::$return_type(a $op b));
+        $vm.stack.pop();
+        $vm.stack.pop();
+        $vm.push(result);
     }};
 }
 
-#[derive(Debug, Clone, PartialEq)]
+const FRAMES_MAX: usize = 64;
+//each frame can address at most 256 slots
+const STACK_MAX: usize = FRAMES_MAX * 256;
+
+//objects are only ever handles into the Heap,
+//so values are cheap to copy around
+#[derive(Debug, Clone, Copy)]
 pub enum Value {
     Bool(bool),
     Nil,
     Number(f64),
-    Str(String),
-    //Obj(Object),
+    //integer literals and whatever's made from them, exact
+    //until they overflow, then they turn into Numbers
+    Int(i64),
+    Str(Gc<ObjString>),
+    Function(Gc<Function>),
+    Closure(Gc<Closure>),
+    Class(Gc<RefCell<Class>>),
+    Instance(Gc<RefCell<Instance>>),
+    BoundMethod(Gc<BoundMethod>),
+    Native(Gc<Native>),
+    List(Gc<List>),
+}
+
+//What == means in Lox:
+// - values of different types are never equal, so 1 != "1" and nil != false
+// - numbers compare as IEEE doubles, so NaN != NaN and 0 == -0
+// - ints and floats are both numbers and compare by value, exactly, so
+//   1 == 1.0 but 9007199254740993 != 9007199254740992.0. <, >, <= and >=
+//   are exact in the same way
+// - strings compare by contents, which interning makes a pointer compare
+// - everything else is equal only to itself. Two instances with the same
+//   fields are different, and so are two bound methods pulled off the same
+//   instance, since each property access makes a new one
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Bool(a), Bool(b)) => a == b,
+            (Nil, Nil) => true,
+            (Number(a), Number(b)) => a == b,
+            (Int(a), Int(b)) => a == b,
+            (Int(a), Number(b)) | (Number(b), Int(a)) => {
+                cmp_int_float(*a, *b) == Some(Ordering::Equal)
+            }
+            //interned, so same contents means same object
+            (Str(a), Str(b)) => Gc::ptr_eq(*a, *b),
+            //functions are only equal to themselves
+            (Value::Function(a), Value::Function(b)) => Gc::ptr_eq(*a, *b),
+            (Value::Closure(a), Value::Closure(b)) => Gc::ptr_eq(*a, *b),
+            (Value::Class(a), Value::Class(b)) => Gc::ptr_eq(*a, *b),
+            (Value::Instance(a), Value::Instance(b)) => Gc::ptr_eq(*a, *b),
+            (Value::BoundMethod(a), Value::BoundMethod(b)) => Gc::ptr_eq(*a, *b),
+            (Value::Native(a), Value::Native(b)) => Gc::ptr_eq(*a, *b),
+            (Value::List(a), Value::List(b)) => Gc::ptr_eq(*a, *b),
+            _ => false,
+        }
+    }
 }
 
-//CI implementation uses a separate Object type,
-//revisit later
-//pub enum Object {
-//   Str(String),
-//}
+//what the stack is made of, NanBoxes are half the size but
+//have to be packed and unpacked on the way in and out
+#[cfg(not(feature = "nan_boxing"))]
+type Slot = Value;
+#[cfg(feature = "nan_boxing")]
+type Slot = NanBox;
+
+//slots is the index of the frame's first stack slot,
+//where the function being called lives
+struct CallFrame {
+    closure: Gc<Closure>,
+    ip: usize,
+    slots: usize,
+}
 
 pub struct Vm {
-    pub pc: usize,
-    pub stack: ArrayVec<Value, 256>,
+    frames: ArrayVec<CallFrame, FRAMES_MAX>,
+    //Vec since STACK_MAX values is too big to keep inline. Only
+    //touched through push, pop and friends, which do the packing
+    pub(crate) stack: Vec<Slot>,
+    pub globals: Table<Value>,
+    //every live string, weak so interning alone doesn't keep one alive
+    strings: Table<()>,
+    init_string: Gc<ObjString>,
+    //sorted by stack slot so closing can pop from the end,
+    //shared so sibling closures see the same variable
+    open_upvalues: Vec<Gc<RefCell<Upvalue>>>,
+    heap: Heap,
+    //state for the random native, xorshift so it never wants to be zero
+    pub(crate) rng_state: u64,
+    //set by OpWide, gets used up by the next constant that's read
+    wide_operand: usize,
+    //where print goes, stdout unless changed
+    output: Box<dyn Write>,
+    //where input() reads from, None is stdin, which is left unlocked
+    //between reads so the repl can still get at it
+    pub(crate) input: Option<Box<dyn BufRead>>,
 }
 
 #[derive(Debug)]
 pub enum VmError {
-    CompileError,
-    RuntimeError,
+    CompileError(Vec<CompileError>),
+    RuntimeError(RuntimeError),
+}
+
+//line is where the error happened, trace is the call stack
+//at that point with the innermost call first
+#[derive(Debug)]
+pub struct RuntimeError {
+    pub message: String,
+    pub line: usize,
+    pub trace: Vec<TraceFrame>,
+}
+
+//function is None for the top level script
+#[derive(Debug)]
+pub struct TraceFrame {
+    pub line: usize,
+    pub function: Option<String>,
 }
 
+//named after clox's OP_ constants
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug, FromRepr)]
 #[repr(u8)]
 pub enum Op {
     OpConstant,
+    //prefix for any op with a constant operand, its two operand bytes are
+    //the upper bits of that op's index, big endian
+    OpWide,
     OpTrue,
     OpFalse,
     OpNil,
@@ -56,142 +194,960 @@ pub enum Op {
     OpSubtract,
     OpMultiply,
     OpDivide,
+    //`~/`, rounds towards negative infinity, ints stay ints
+    OpFloorDivide,
+    //the remainder that goes with OpFloorDivide, so it takes the divisor's sign
+    OpModulo,
     OpNegate,
     OpNot,
     OpEqual,
+    OpNotEqual,
     OpGreater,
+    OpGreaterEqual,
     OpLess,
+    OpLessEqual,
     OpPrint,
+    OpPop,
+    OpDefineGlobal,
+    OpGetGlobal,
+    OpSetGlobal,
+    OpGetLocal,
+    OpSetLocal,
+    OpJump,
+    OpJumpIfFalse,
+    OpLoop,
+    OpCall,
+    OpClosure,
+    OpGetUpvalue,
+    OpSetUpvalue,
+    OpCloseUpvalue,
+    OpClass,
+    OpGetProperty,
+    OpSetProperty,
+    OpMethod,
+    OpInvoke,
+    OpInherit,
+    OpGetSuper,
+    OpSuperInvoke,
+}
+
+impl Value {
+    //nil and false are falsey, everything else is truthy
+    pub fn is_falsey(&self) -> bool {
+        matches!(self, Nil | Bool(false))
+    }
+
+    //either kind of number as a float, None for anything else
+    pub fn as_f64(&self) -> Option<f64> {
+        match *self {
+            Number(n) => Some(n),
+            Int(n) => Some(n as f64),
+            _ => None,
+        }
+    }
+}
+
+//exact, without rounding the int to a float first. None for NaN
+fn cmp_int_float(int: i64, float: f64) -> Option<Ordering> {
+    //i64's range as floats, both ends are exact powers of two
+    const MIN: f64 = i64::MIN as f64;
+    if float.is_nan() {
+        return None;
+    }
+    if float >= -MIN {
+        return Some(Ordering::Less);
+    }
+    if float < MIN {
+        return Some(Ordering::Greater);
+    }
+
+    //in range, so the whole part converts exactly and
+    //the fraction only matters when the whole parts tie
+    let whole = float.trunc();
+    Some(int.cmp(&(whole as i64)).then(0.0.partial_cmp(&(float - whole))?))
+}
+
+//floor division and the remainder that goes with it, None on
+//division by zero or i64::MIN ~/ -1
+fn floor_div_int(a: i64, b: i64) -> Option<(i64, i64)> {
+    let (quot, rem) = (a.checked_div(b)?, a.checked_rem(b)?);
+    //rust truncates, so round down when the signs differ
+    if rem != 0 && (rem < 0) != (b < 0) {
+        Some((quot - 1, rem + b))
+    } else {
+        Some((quot, rem))
+    }
 }
 
 impl fmt::Display for Value {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Number(inner) => write!(f, "{:.2}", inner),
+            Number(inner) => fmt_float(*inner, f),
+            Int(inner) => write!(f, "{inner}"),
             Str(inner) => write!(f, "{}", inner),
             Bool(inner) => write!(f, "{}", if *inner { "true" } else { "false" }),
             Nil => write!(f, "Nil"),
+            Value::Function(inner) => write!(f, "{inner}"),
+            Value::Closure(inner) => write!(f, "{}", inner.function),
+            Value::Class(inner) => write!(f, "{}", inner.borrow().name),
+            Value::Instance(inner) => write!(f, "{} instance", inner.borrow().class.borrow().name),
+            Value::BoundMethod(inner) => write!(f, "{}", inner.method.function),
+            Value::Native(inner) => write!(f, "{inner}"),
+            Value::List(inner) => write!(f, "{inner}"),
         }
     }
 }
 
+//shortest form that reads back as the same float, and whole numbers
+//without a fraction, so 12.0 is 12 and 0.1 is 0.1. Really big or small
+//ones switch to an exponent at the same points javascript does
+fn fmt_float(n: f64, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    if n.is_infinite() {
+        write!(f, "{}", if n > 0.0 { "Infinity" } else { "-Infinity" })
+    } else if n != 0.0 && !(1e-6..1e21).contains(&n.abs()) {
+        write!(f, "{n:e}")
+    } else {
+        write!(f, "{n}")
+    }
+}
+
 #[derive(Default, Debug)]
 pub struct Chunk {
     pub bytecode: Vec<u8>,
     pub const_pool: Vec<Value>,
-    //this field isn't used?
-    //lines: Vec<u32>,
+    //run-length encoded as (line, number of bytes on that line),
+    //since most lines compile to a whole run of bytes
+    pub lines: Vec<(usize, usize)>,
 }
 
 impl Vm {
-    pub fn interpret(&mut self, chunk: &Chunk) -> Result<(), VmError> {
-        while self.pc < chunk.bytecode.len() {
-            let instr = Op::from_repr(chunk.bytecode[self.pc]).unwrap();
+    pub(crate) fn interpret(&mut self, function: Gc<Function>) -> Result<(), VmError> {
+        let closure = self.alloc(Closure {
+            function,
+            upvalues: Vec::new(),
+        });
+        self.push(Value::Closure(closure));
+        self.call(closure, 0)?;
+
+        self.run().map(|_| ())
+    }
+
+    pub(crate) fn push(&mut self, val: Value) {
+        let slot = Slot::pack(val, &mut self.heap);
+        self.stack.push(slot);
+    }
+
+    //panics if the stack's empty
+    pub(crate) fn pop(&mut self) -> Value {
+        self.stack.pop().unwrap().unpack()
+    }
+
+    //pops everything from base up, bottom first
+    pub(crate) fn pop_from(&mut self, base: usize) -> Vec<Value> {
+        self.stack.drain(base..).map(StackSlot::unpack).collect()
+    }
+
+    //for calling into the vm from outside, the callee and its
+    //arguments have to be pushed onto the stack first
+    pub(crate) fn call_from_host(&mut self, arg_count: u8) -> Result<Value, VmError> {
+        let callee = self.peek(arg_count as usize);
+        self.call_value(callee, arg_count)?;
+
+        //a class without an initializer is done as soon as it's called
+        if self.frames.is_empty() {
+            return Ok(self.pop());
+        }
+        self.run()
+    }
+
+    //doesn't allocate, a name that was never interned can't be a global
+    pub(crate) fn get_global(&self, name: &str) -> Option<Value> {
+        let name = self.strings.find_string(name, hash_string(name))?;
+        self.globals.get(name)
+    }
+
+    pub(crate) fn set_global(&mut self, name: &str, val: Value) {
+        //interning the name could collect val
+        self.push(val);
+        let name = self.intern(name);
+        self.globals.set(name, val);
+        self.stack.pop();
+    }
+
+    //returns whatever the outermost function returned
+    fn run(&mut self) -> Result<Value, VmError> {
+        loop {
+            let instr = Op::from_repr(self.read_byte()).unwrap();
 
             match instr {
                 OpConstant => {
-                    self.pc += 1;
-                    self.stack
-                        .push(chunk.const_pool[chunk.bytecode[self.pc] as usize].clone());
+                    let val = *self.read_constant();
+                    self.push(val);
+                }
+
+                OpWide => {
+                    let high = self.read_byte() as usize;
+                    let mid = self.read_byte() as usize;
+                    self.wide_operand = high << 16 | mid << 8;
                 }
 
                 OpReturn => {
-                    println!("{}", self.stack.last().unwrap());
+                    let result = self.pop();
+                    let frame = self.frames.pop().unwrap();
+                    self.close_upvalues(frame.slots);
+
+                    //discard the callee's arguments and locals
+                    self.stack.truncate(frame.slots);
+                    if self.frames.is_empty() {
+                        return Ok(result);
+                    }
+                    self.push(result);
                 }
 
-                OpNegate => {
-                    //I think this'll work
-                    if let Number(val) = self.stack.last().unwrap() {
-                        *self.stack.last_mut().unwrap() = Number(-val);
+                OpCall => {
+                    self.safepoint();
+                    let arg_count = self.read_byte();
+                    let callee = self.peek(arg_count as usize);
+                    self.call_value(callee, arg_count)?;
+                }
+
+                OpClosure => {
+                    let Value::Function(function) = *self.read_constant() else {
+                        unreachable!();
+                    };
+
+                    let mut upvalues = Vec::with_capacity(function.upvalue_count);
+                    for _ in 0..function.upvalue_count {
+                        let is_local = self.read_byte() == 1;
+                        let index = self.read_byte() as usize;
+
+                        if is_local {
+                            let slot = self.frame().slots + index;
+                            upvalues.push(self.capture_upvalue(slot));
+                        } else {
+                            upvalues.push(self.frame().closure.upvalues[index]);
+                        }
+                    }
+
+                    let closure = self.alloc(Closure { function, upvalues });
+                    self.push(Value::Closure(closure));
+                }
+
+                OpGetUpvalue => {
+                    let idx = self.read_byte() as usize;
+                    let val = match &*self.frame().closure.upvalues[idx].borrow() {
+                        Upvalue::Open(slot) => self.stack_get(*slot),
+                        Upvalue::Closed(val) => *val,
+                    };
+                    self.push(val);
+                }
+
+                OpSetUpvalue => {
+                    let idx = self.read_byte() as usize;
+                    let val = self.peek(0);
+                    let upvalue = self.frame().closure.upvalues[idx];
+                    let mut upvalue = upvalue.borrow_mut();
+                    match &mut *upvalue {
+                        Upvalue::Open(slot) => self.stack_set(*slot, val),
+                        Upvalue::Closed(closed) => *closed = val,
+                    };
+                }
+
+                OpCloseUpvalue => {
+                    self.close_upvalues(self.stack.len() - 1);
+                    self.stack.pop();
+                }
+
+                OpClass => {
+                    let name = self.read_name();
+                    let class = self.alloc(RefCell::new(Class {
+                        name,
+                        methods: Table::new(),
+                    }));
+                    self.push(Value::Class(class));
+                }
+
+                OpGetProperty => {
+                    let name = self.read_name();
+                    let Value::Instance(instance) = self.peek(0) else {
+                        return Err(self.runtime_error("Only instances have properties"));
+                    };
+
+                    //fields shadow methods
+                    let field = instance.borrow().fields.get(name);
+                    if let Some(val) = field {
+                        self.stack.pop();
+                        self.push(val);
                     } else {
-                        panic!("Operand must be a number");
+                        let class = instance.borrow().class;
+                        self.bind_method(&class, name)?;
                     }
                 }
 
+                OpSetProperty => {
+                    let name = self.read_name();
+                    let Value::Instance(instance) = self.peek(1) else {
+                        return Err(self.runtime_error("Only instances have fields"));
+                    };
+
+                    //leave the assigned value as the result of the expression
+                    let val = self.pop();
+                    instance.borrow_mut().fields.set(name, val);
+                    self.stack.pop();
+                    self.push(val);
+                }
+
+                OpMethod => {
+                    let name = self.read_name();
+                    let Value::Closure(method) = self.pop() else {
+                        unreachable!();
+                    };
+                    let Value::Class(class) = self.peek(0) else {
+                        unreachable!();
+                    };
+                    class.borrow_mut().methods.set(name, method);
+                }
+
+                OpInvoke => {
+                    let name = self.read_name();
+                    let arg_count = self.read_byte();
+                    self.invoke(name, arg_count)?;
+                }
+
+                //methods are copied down when the class is declared,
+                //so lookups never have to walk the inheritance chain
+                OpInherit => {
+                    let Value::Class(superclass) = self.peek(1) else {
+                        return Err(self.runtime_error("Superclass must be a class"));
+                    };
+                    let Value::Class(subclass) = self.pop() else {
+                        unreachable!();
+                    };
+
+                    let methods = superclass.borrow().methods.clone();
+                    subclass.borrow_mut().methods.add_all(&methods);
+                }
+
+                OpGetSuper => {
+                    let name = self.read_name();
+                    let Value::Class(superclass) = self.pop() else {
+                        unreachable!();
+                    };
+                    self.bind_method(&superclass, name)?;
+                }
+
+                OpSuperInvoke => {
+                    let name = self.read_name();
+                    let arg_count = self.read_byte();
+                    let Value::Class(superclass) = self.pop() else {
+                        unreachable!();
+                    };
+                    self.invoke_from_class(&superclass, name, arg_count)?;
+                }
+
+                OpNegate => {
+                    //I think this'll work
+                    let negated = match self.peek(0) {
+                        Number(val) => Number(-val),
+                        //only i64::MIN has nowhere to go
+                        Int(val) => val.checked_neg().map_or(Number(-(val as f64)), Int),
+                        _ => return Err(self.runtime_error("Operand must be a number")),
+                    };
+                    self.stack.pop();
+                    self.push(negated);
+                }
+
                 OpAdd => {
                     match (
-                        self.stack.last().clone().unwrap(),
-                        self.stack.get(self.stack.len().wrapping_sub(2)).clone().unwrap()) {
+                        self.peek(0),
+                        self.peek(1)) {
 
                         (Value::Str(_), Value::Str(_))
-                        | (Value::Number(_), Value::Str(_))
-                        | (Value::Str(_), Value::Number(_)) => {
+                        | (Value::Number(_) | Value::Int(_), Value::Str(_))
+                        | (Value::Str(_), Value::Number(_) | Value::Int(_)) => {
                                                    
-                            let (b, a) = (self.stack.pop().unwrap(), self.stack.pop().unwrap());
-                            self.stack.push(Str(format!("{a}{b}")));
+                            let (b, a) = (self.pop(), self.pop());
+                            let string = self.intern_owned(format!("{a}{b}"));
+                            self.push(Str(string));
                         },
 
-                        (Value::Number(_), Value::Number(_)) => {
-                            binary_op!(self.stack, +, Number);
+                        (Value::Number(_) | Value::Int(_), Value::Number(_) | Value::Int(_)) => {
+                            arithmetic_op!(self, +, checked_add);
                         }
 
                         _ => {
-                            panic!("Invalid operand type");
+                            return Err(self.runtime_error("Operands must be two numbers or strings"));
                         }
                     }
                 }
 
                 OpSubtract => {
-                    binary_op!(self.stack, -, Number);
+                    arithmetic_op!(self, -, checked_sub);
                 }
 
                 OpMultiply => {
-                    binary_op!(self.stack, *, Number);
+                    arithmetic_op!(self, *, checked_mul);
                 }
 
+                //always a float, 7 / 2 is 3.5
                 OpDivide => {
-                    binary_op!(self.stack, /, Number);
+                    let (Some(a), Some(b)) = (self.peek(1).as_f64(), self.peek(0).as_f64()) else {
+                        return Err(self.runtime_error("Operands must be numbers"));
+                    };
+                    self.stack.pop();
+                    self.stack.pop();
+                    self.push(Number(a / b));
+                }
+
+                OpFloorDivide | OpModulo => {
+                    let (a, b) = (self.peek(1), self.peek(0));
+                    let (quot, rem) = match (a, b) {
+                        (Int(_), Int(0)) => {
+                            return Err(self.runtime_error("Integer division by zero"));
+                        }
+                        (Int(a), Int(b)) => match floor_div_int(a, b) {
+                            Some((quot, rem)) => (Int(quot), Int(rem)),
+                            //i64::MIN ~/ -1, which divides evenly
+                            None => (Number(-(a as f64)), Int(0)),
+                        },
+                        (a, b) => {
+                            let (Some(a), Some(b)) = (a.as_f64(), b.as_f64()) else {
+                                return Err(self.runtime_error("Operands must be numbers"));
+                            };
+                            //same rounding fix as the ints
+                            let rem = a % b;
+                            let rem = if rem != 0.0 && (rem < 0.0) != (b < 0.0) { rem + b } else { rem };
+                            (Number((a / b).floor()), Number(rem))
+                        }
+                    };
+                    self.stack.pop();
+                    self.stack.pop();
+                    self.push(if matches!(instr, OpFloorDivide) { quot } else { rem });
                 }
 
                 OpTrue => {
-                    self.stack.push(Bool(true));
+                    self.push(Bool(true));
                 }
 
                 OpFalse => {
-                    self.stack.push(Bool(false));
+                    self.push(Bool(false));
                 }
 
                 OpNil => {
-                    self.stack.push(Nil);
+                    self.push(Nil);
                 }
 
                 OpNot => {
-                    let val = self.stack.pop().unwrap();
-                    self.stack.push(Bool(val == Bool(false) || val == Nil));
+                    let val = self.pop();
+                    self.push(Bool(val.is_falsey()));
                 }
 
                 OpEqual => {
-                    let a = self.stack.pop().unwrap();
-                    let b = self.stack.pop().unwrap();
-                    self.stack.push(Bool(a == b));
+                    let a = self.pop();
+                    let b = self.pop();
+                    self.push(Bool(a == b));
+                }
+
+                OpNotEqual => {
+                    let a = self.pop();
+                    let b = self.pop();
+                    self.push(Bool(a != b));
                 }
 
+                //comparisons follow IEEE 754, so all of them are false against NaN
                 OpGreater => {
-                    binary_op!(self.stack, >, Bool);
+                    comparison_op!(self, >);
+                }
+
+                OpGreaterEqual => {
+                    comparison_op!(self, >=);
                 }
 
                 OpLess => {
-                    binary_op!(self.stack, <, Bool);
-                } //_ => {}
+                    comparison_op!(self, <);
+                }
+
+                OpLessEqual => {
+                    comparison_op!(self, <=);
+                }
 
                 OpPrint => {
-                    //two newlines here or one?
-                    println!("{}", self.stack.pop().unwrap());
+                    let val = self.pop();
+                    if let Err(err) = writeln!(self.output, "{val}") {
+                        return Err(self.runtime_error(&format!("Unable to print: {err}")));
+                    }
+                }
+
+                OpPop => {
+                    self.stack.pop();
+                }
+
+                OpDefineGlobal => {
+                    let name = self.read_name();
+                    let val = self.pop();
+                    self.globals.set(name, val);
+                }
+
+                OpGetGlobal => {
+                    let name = self.read_name();
+                    match self.globals.get(name) {
+                        Some(val) => self.push(val),
+                        None => {
+                            return Err(self.runtime_error(&format!("Undefined variable '{name}'")));
+                        }
+                    }
+                }
+
+                OpSetGlobal => {
+                    let name = self.read_name();
+                    //assignment is an expression, so the value stays on the stack
+                    let val = self.peek(0);
+                    if self.globals.set(name, val) {
+                        //assigning doesn't declare, so undo it
+                        self.globals.delete(name);
+                        return Err(self.runtime_error(&format!("Undefined variable '{name}'")));
+                    }
+                }
+
+                //local slots are relative to the start of the frame
+                OpGetLocal => {
+                    let slot = self.frame().slots + self.read_byte() as usize;
+                    //copied as is, unpacking and repacking could box a big int again
+                    self.stack.push(self.stack[slot]);
+                }
+
+                OpSetLocal => {
+                    let slot = self.frame().slots + self.read_byte() as usize;
+                    self.stack[slot] = *self.stack.last().unwrap();
+                }
+
+                //jump offsets are relative to the end of the operand
+                OpJump => {
+                    let offset = self.read_short();
+                    self.frame_mut().ip += offset;
+                }
 
+                OpJumpIfFalse => {
+                    let offset = self.read_short();
+                    if self.peek(0).is_falsey() {
+                        self.frame_mut().ip += offset;
+                    }
+                }
+
+                OpLoop => {
+                    self.safepoint();
+                    let offset = self.read_short();
+                    self.frame_mut().ip -= offset;
                 }
             }
+        }
+    }
+
+    pub fn new() -> Vm {
+        let mut heap = Heap::new();
+        let mut strings = Table::new();
+        let init_string = heap.alloc(ObjString::new("init".to_owned()));
+        strings.set(init_string, ());
+
+        let mut vm = Vm {
+            frames: ArrayVec::new(),
+            stack: Vec::with_capacity(STACK_MAX),
+            globals: Table::new(),
+            strings,
+            init_string,
+            open_upvalues: Vec::new(),
+            heap,
+            rng_state: native::time_seed(),
+            wide_operand: 0,
+            output: Box::new(io::stdout()),
+            input: None,
+        };
+        native::define_stdlib(&mut vm);
+        vm
+    }
+
+    pub fn set_output(&mut self, output: impl Write + 'static) {
+        self.output = Box::new(output);
+    }
+
+    pub fn set_input(&mut self, input: impl BufRead + 'static) {
+        self.input = Some(Box::new(input));
+    }
+
+    pub(crate) fn set_stress_gc(&mut self, stress: bool) {
+        self.heap.stress = stress;
+    }
+
+    pub(crate) fn define_native(&mut self, name: &str, arity: u8, function: NativeFn) {
+        self.define_native_function(name, arity, NativeFunction::Vm(function));
+    }
 
-            self.pc += 1;
+    pub(crate) fn define_native_function(&mut self, name: &str, arity: u8, function: NativeFunction) {
+        let name = self.intern(name);
+        let native = self.alloc(Native {
+            name,
+            arity,
+            function,
+        });
+        self.globals.set(name, Value::Native(native));
+    }
+
+    pub(crate) fn intern(&mut self, chars: &str) -> Gc<ObjString> {
+        self.intern_rooted(chars, |_| {})
+    }
+
+    //skips the copy when the caller already owns the string
+    pub(crate) fn intern_owned(&mut self, chars: String) -> Gc<ObjString> {
+        if let Some(interned) = self.strings.find_string(&chars, hash_string(&chars)) {
+            return interned;
+        }
+
+        let string = self.alloc(ObjString::new(chars));
+        self.strings.set(string, ());
+        string
+    }
+
+    pub(crate) fn intern_rooted(
+        &mut self,
+        chars: &str,
+        extra_roots: impl FnOnce(&mut Tracer),
+    ) -> Gc<ObjString> {
+        if let Some(interned) = self.strings.find_string(chars, hash_string(chars)) {
+            return interned;
+        }
+
+        let string = self.alloc_rooted(ObjString::new(chars.to_owned()), extra_roots);
+        self.strings.set(string, ());
+        string
+    }
+
+    pub(crate) fn alloc<T: Trace>(&mut self, value: T) -> Gc<T> {
+        self.alloc_rooted(value, |_| {})
+    }
+
+    //extra_roots marks anything the caller is holding onto that the vm
+    //can't see, the value being allocated is always treated as a root
+    pub(crate) fn alloc_rooted<T: Trace>(
+        &mut self,
+        value: T,
+        extra_roots: impl FnOnce(&mut Tracer),
+    ) -> Gc<T> {
+        if self.heap.should_collect() {
+            self.collect_garbage(|tracer| {
+                value.trace(tracer);
+                extra_roots(tracer);
+            });
+        }
+
+        self.heap.alloc(value)
+    }
+
+    //Packing a value can allocate without checking whether to collect, so
+    //loops and calls check instead. Every live value is somewhere the vm
+    //can see at both, so nothing extra needs rooting
+    fn safepoint(&mut self) {
+        if Slot::ALLOCATES && self.heap.should_collect() {
+            self.collect_garbage(|_| {});
+        }
+    }
+
+    fn collect_garbage(&mut self, extra_roots: impl FnOnce(&mut Tracer)) {
+        let (stack, frames, globals, open_upvalues) =
+            (&self.stack, &self.frames, &self.globals, &self.open_upvalues);
+        let (strings, init_string) = (&mut self.strings, self.init_string);
+
+        self.heap.collect(
+            |tracer| {
+                for slot in stack {
+                    slot.trace(tracer);
+                }
+                for frame in frames {
+                    tracer.mark(frame.closure);
+                }
+                for (name, val) in globals.iter() {
+                    tracer.mark(name);
+                    tracer.mark_value(&val);
+                }
+                for upvalue in open_upvalues {
+                    tracer.mark(*upvalue);
+                }
+                tracer.mark(init_string);
+                extra_roots(tracer);
+            },
+            //strings nothing else refers to get dropped from the interner
+            || strings.retain(|string, _| string.is_marked()),
+        );
+    }
+
+    fn call_value(&mut self, callee: Value, arg_count: u8) -> Result<(), VmError> {
+        match callee {
+            Value::Closure(closure) => self.call(closure, arg_count),
+
+            Value::Class(class) => {
+                //the instance replaces the class in the callee slot,
+                //which is where init expects to find `this`
+                let slot = self.stack.len() - arg_count as usize - 1;
+                let instance = self.alloc(RefCell::new(Instance {
+                    class,
+                    fields: Table::new(),
+                }));
+                self.stack_set(slot, Value::Instance(instance));
+
+                let initializer = class.borrow().methods.get(self.init_string);
+                match initializer {
+                    Some(initializer) => self.call(initializer, arg_count),
+                    None if arg_count != 0 => Err(self.runtime_error(&format!(
+                        "Expected 0 arguments but got {arg_count}"
+                    ))),
+                    None => Ok(()),
+                }
+            }
+
+            Value::BoundMethod(bound) => {
+                let slot = self.stack.len() - arg_count as usize - 1;
+                self.stack_set(slot, bound.receiver);
+                self.call(bound.method, arg_count)
+            }
+
+            Value::Native(native) => {
+                if arg_count != native.arity {
+                    return Err(self.runtime_error(&format!(
+                        "Expected {} arguments but got {}",
+                        native.arity, arg_count
+                    )));
+                }
+
+                //the args stay on the stack until the call's done so they can't be collected
+                let args_start = self.stack.len() - arg_count as usize;
+                let result = match native.function {
+                    NativeFunction::Vm(function) => {
+                        let args: Vec<Value> =
+                            self.stack[args_start..].iter().map(|slot| slot.unpack()).collect();
+                        function(self, &args)
+                    }
+                    //host natives only get copies, and what they hand back
+                    //is only made into a vm value once they've returned
+                    NativeFunction::Host(function) => {
+                        let args: Vec<OwnedValue> = self.stack[args_start..]
+                            .iter()
+                            .map(|slot| interpreter::owned_value(slot.unpack()))
+                            .collect();
+                        function(&args).and_then(|result| {
+                            interpreter::vm_value(self, &result).map_err(|err| err.to_string())
+                        })
+                    }
+                }
+                .map_err(|msg| self.runtime_error(&msg))?;
+
+                self.stack.truncate(args_start - 1);
+                self.push(result);
+                Ok(())
+            }
+
+            _ => Err(self.runtime_error("Can only call functions and classes")),
+        }
+    }
+
+    fn invoke(&mut self, name: Gc<ObjString>, arg_count: u8) -> Result<(), VmError> {
+        let Value::Instance(instance) = self.peek(arg_count as usize) else {
+            return Err(self.runtime_error("Only instances have methods"));
+        };
+
+        //a field holding a function gets called like any other value
+        let field = instance.borrow().fields.get(name);
+        if let Some(field) = field {
+            let slot = self.stack.len() - arg_count as usize - 1;
+            self.stack_set(slot, field);
+            return self.call_value(field, arg_count);
+        }
+
+        let class = instance.borrow().class;
+        self.invoke_from_class(&class, name, arg_count)
+    }
+
+    fn invoke_from_class(
+        &mut self,
+        class: &Gc<RefCell<Class>>,
+        name: Gc<ObjString>,
+        arg_count: u8,
+    ) -> Result<(), VmError> {
+        let method = class.borrow().methods.get(name);
+        match method {
+            Some(method) => self.call(method, arg_count),
+            None => Err(self.runtime_error(&format!("Undefined property '{name}'"))),
+        }
+    }
+
+    //replaces the instance on top of the stack with one of its class' methods
+    fn bind_method(&mut self, class: &Gc<RefCell<Class>>, name: Gc<ObjString>) -> Result<(), VmError> {
+        let method = class.borrow().methods.get(name);
+        let Some(method) = method else {
+            return Err(self.runtime_error(&format!("Undefined property '{name}'")));
+        };
+
+        let receiver = self.pop();
+        let bound = self.alloc(BoundMethod { receiver, method });
+        self.push(Value::BoundMethod(bound));
+        Ok(())
+    }
+
+    fn call(&mut self, closure: Gc<Closure>, arg_count: u8) -> Result<(), VmError> {
+        let function = &closure.function;
+        if arg_count != function.arity {
+            return Err(self.runtime_error(&format!(
+                "Expected {} arguments but got {}",
+                function.arity, arg_count
+            )));
+        }
+
+        if self.frames.is_full() {
+            return Err(self.runtime_error("Stack overflow"));
         }
 
+        //the callee and its arguments become the bottom of the new frame
+        let slots = self.stack.len() - arg_count as usize - 1;
+        self.frames.push(CallFrame {
+            closure,
+            ip: 0,
+            slots,
+        });
         Ok(())
     }
 
-    pub fn new() -> Vm {
-        Vm {
-            pc: 0,
-            stack: ArrayVec::new(),
+    fn capture_upvalue(&mut self, slot: usize) -> Gc<RefCell<Upvalue>> {
+        let pos = self
+            .open_upvalues
+            .partition_point(|upvalue| matches!(*upvalue.borrow(), Upvalue::Open(s) if s < slot));
+
+        if let Some(existing) = self.open_upvalues.get(pos) {
+            if matches!(*existing.borrow(), Upvalue::Open(s) if s == slot) {
+                return *existing;
+            }
         }
+
+        let upvalue = self.alloc(RefCell::new(Upvalue::Open(slot)));
+        self.open_upvalues.insert(pos, upvalue);
+        upvalue
+    }
+
+    //moves every variable at or above last off the stack and into its upvalue
+    fn close_upvalues(&mut self, last: usize) {
+        while let Some(upvalue) = self.open_upvalues.last() {
+            let slot = match *upvalue.borrow() {
+                Upvalue::Open(slot) if slot >= last => slot,
+                _ => break,
+            };
+
+            *upvalue.borrow_mut() = Upvalue::Closed(self.stack_get(slot));
+            self.open_upvalues.pop();
+        }
+    }
+
+    fn frame(&self) -> &CallFrame {
+        self.frames.last().unwrap()
+    }
+
+    fn frame_mut(&mut self) -> &mut CallFrame {
+        self.frames.last_mut().unwrap()
+    }
+
+    fn peek(&self, distance: usize) -> Value {
+        self.stack[self.stack.len() - 1 - distance].unpack()
+    }
+
+    fn stack_get(&self, idx: usize) -> Value {
+        self.stack[idx].unpack()
+    }
+
+    fn stack_set(&mut self, idx: usize, val: Value) {
+        self.stack[idx] = Slot::pack(val, &mut self.heap);
+    }
+
+    fn read_byte(&mut self) -> u8 {
+        let frame = self.frame_mut();
+        frame.ip += 1;
+        frame.closure.function.chunk.bytecode[frame.ip - 1]
+    }
+
+    fn read_short(&mut self) -> usize {
+        let (hi, lo) = (self.read_byte(), self.read_byte());
+        u16::from_be_bytes([hi, lo]) as usize
+    }
+
+    fn read_constant(&mut self) -> &Value {
+        let idx = mem::take(&mut self.wide_operand) | self.read_byte() as usize;
+        &self.frame().closure.function.chunk.const_pool[idx]
+    }
+
+    //operand is an index into the const pool holding the variable's name
+    fn read_name(&mut self) -> Gc<ObjString> {
+        match *self.read_constant() {
+            Str(name) => name,
+            _ => unreachable!(),
+        }
+    }
+
+    //unwinds the whole vm, so it's ready to run something else
+    fn runtime_error(&mut self, msg: &str) -> VmError {
+        let trace: Vec<_> = self
+            .frames
+            .iter()
+            .rev()
+            .map(|frame| {
+                let function = frame.closure.function;
+                TraceFrame {
+                    //ip has already moved past the failing instruction
+                    line: function.chunk.line_at(frame.ip.saturating_sub(1)),
+                    function: function.name.map(|name| name.chars.clone()),
+                }
+            })
+            .collect();
+
+        self.stack.clear();
+        self.frames.clear();
+        self.open_upvalues.clear();
+
+        VmError::RuntimeError(RuntimeError {
+            message: msg.to_owned(),
+            line: trace.first().map_or(0, |frame| frame.line),
+            trace,
+        })
+    }
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)?;
+        for frame in &self.trace {
+            match &frame.function {
+                Some(name) => write!(f, "\n[line {}] in {}()", frame.line, name)?,
+                None => write!(f, "\n[line {}] in script", frame.line)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VmError::CompileError(errors) => {
+                for (i, err) in errors.iter().enumerate() {
+                    if i > 0 {
+                        writeln!(f)?;
+                    }
+                    write!(f, "{err}")?;
+                }
+                Ok(())
+            }
+            VmError::RuntimeError(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl Default for Vm {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -199,23 +1155,77 @@ impl Vm {
 impl fmt::Display for Chunk {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let mut i = 0;
+        //upper bits from an OpWide, for the instruction after it
+        let mut wide = 0;
 
         while i < self.bytecode.len() {
             //fix this bullshit later
             let opcode = Op::from_repr(self.bytecode[i]).unwrap();
 
             //rust throws a fit if I don't put a stupid underscore beforehand
-            _ = write!(f, "{:04} {:?}", i, opcode);
+            let line = self.line_at(i);
+            if i > 0 && line == self.line_at(i - 1) {
+                _ = write!(f, "{:04}    | {:?}", i, opcode);
+            } else {
+                _ = write!(f, "{:04} {:4} {:?}", i, line, opcode);
+            }
 
             match opcode {
-                OpConstant => {
+                OpConstant | OpDefineGlobal | OpGetGlobal | OpSetGlobal | OpClass
+                | OpGetProperty | OpSetProperty | OpMethod | OpGetSuper => {
+                    i += 1;
+                    let idx = mem::take(&mut wide) | self.bytecode[i] as usize;
+                    _ = write!(f, "    {} '{}'", idx, self.const_pool[idx]);
+                }
+
+                OpWide => {
+                    wide = (self.bytecode[i + 1] as usize) << 16 | (self.bytecode[i + 2] as usize) << 8;
+                    _ = write!(f, "    {}", wide);
+                    i += 2;
+                }
+
+                OpGetLocal | OpSetLocal | OpCall | OpGetUpvalue | OpSetUpvalue => {
                     i += 1;
+                    _ = write!(f, "    {}", self.bytecode[i]);
+                }
+
+                OpInvoke | OpSuperInvoke => {
+                    let idx = mem::take(&mut wide) | self.bytecode[i + 1] as usize;
                     _ = write!(
                         f,
-                        "    {} '{}'",
-                        self.bytecode[i], self.const_pool[self.bytecode[i] as usize]
+                        "    ({} args) {} '{}'",
+                        self.bytecode[i + 2],
+                        idx,
+                        self.const_pool[idx]
                     );
-                    //i += 1;
+                    i += 2;
+                }
+
+                OpClosure => {
+                    i += 1;
+                    let idx = mem::take(&mut wide) | self.bytecode[i] as usize;
+                    let function = &self.const_pool[idx];
+                    _ = write!(f, "    {} '{}'", idx, function);
+
+                    if let Value::Function(function) = function {
+                        for _ in 0..function.upvalue_count {
+                            let kind = if self.bytecode[i + 1] == 1 { "local" } else { "upvalue" };
+                            _ = write!(f, "\n{:04}    |     {} {}", i + 1, kind, self.bytecode[i + 2]);
+                            i += 2;
+                        }
+                    }
+                }
+
+                OpJump | OpJumpIfFalse | OpLoop => {
+                    let offset =
+                        u16::from_be_bytes([self.bytecode[i + 1], self.bytecode[i + 2]]) as usize;
+                    let target = if matches!(opcode, OpLoop) {
+                        i + 3 - offset
+                    } else {
+                        i + 3 + offset
+                    };
+                    _ = write!(f, "    {} -> {}", i, target);
+                    i += 2;
                 }
 
                 _ => {}
@@ -226,6 +1236,13 @@ impl fmt::Display for Chunk {
             i += 1;
         }
 
+        //functions each have their own chunk, so list those after
+        for val in &self.const_pool {
+            if let Value::Function(function) = val {
+                _ = write!(f, "\n== {} ==\n{}", function, function.chunk);
+            }
+        }
+
         Ok(())
     }
 }
@@ -235,10 +1252,31 @@ impl Chunk {
         Self {
             bytecode: Vec::new(),
             const_pool: Vec::new(),
-            //lines: Vec::new(),
+            lines: Vec::new(),
         }
     }
 
+    pub fn write(&mut self, byte: u8, line: usize) {
+        self.bytecode.push(byte);
+
+        match self.lines.last_mut() {
+            Some((last_line, count)) if *last_line == line => *count += 1,
+            _ => self.lines.push((line, 1)),
+        }
+    }
+
+    pub fn line_at(&self, offset: usize) -> usize {
+        let mut start = 0;
+        for &(line, count) in &self.lines {
+            start += count;
+            if offset < start {
+                return line;
+            }
+        }
+
+        self.lines.last().map_or(0, |&(line, _)| line)
+    }
+
     /*
     pub fn new(bytecode: Vec<u8>, const_pool: Vec<f64>) -> Self {
         Self {
@@ -0,0 +1,158 @@
+use kara::{Error, Interpreter, OutputBuffer};
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+//Runs every script under tests/lox and checks it against the comments in it,
+//in the Crafting Interpreters test format:
+//
+//    print 1 + 2; // expect: 3
+//    print nil.x; // expect runtime error: Only instances have properties
+//    var a = ;    // [line 3] Error at ';': Expected expression
+//    var b = ;    // Error at ';': Expected expression
+//
+//The last form is the same as the one before it with the comment's own line.
+//Every script also gets run with the collector stressed, which should never
+//change what it does
+const SCRIPT_DIR: &str = "tests/lox";
+
+#[derive(Debug, Default, PartialEq)]
+struct Outcome {
+    output: Vec<String>,
+    compile_errors: Vec<String>,
+    //the line and the message
+    runtime_error: Option<(usize, String)>,
+}
+
+#[test]
+fn golden_scripts() {
+    let scripts = find_scripts(Path::new(SCRIPT_DIR));
+    assert!(!scripts.is_empty(), "no scripts found under {SCRIPT_DIR}");
+
+    let mut failures = String::new();
+    for path in &scripts {
+        let source = fs::read_to_string(path).unwrap();
+        let expected = expectations(&source);
+
+        for stress in [false, true] {
+            let actual = run(&source, stress);
+            if actual != expected {
+                let mode = if stress { " (stress gc)" } else { "" };
+                _ = writeln!(failures, "{}{mode}:", path.display());
+                report_diff(&mut failures, &expected, &actual);
+            }
+        }
+    }
+
+    assert!(
+        failures.is_empty(),
+        "golden scripts didn't match their expectations\n\n{failures}"
+    );
+}
+
+//sorted so failures always come out in the same order
+fn find_scripts(dir: &Path) -> Vec<PathBuf> {
+    let mut scripts = Vec::new();
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            scripts.extend(find_scripts(&path));
+        } else if path
+            .extension()
+            .is_some_and(|ext| ext == "lox" || ext == "kara")
+        {
+            scripts.push(path);
+        }
+    }
+
+    scripts.sort();
+    scripts
+}
+
+fn expectations(source: &str) -> Outcome {
+    let mut expected = Outcome::default();
+
+    for (idx, line) in source.lines().enumerate() {
+        let line_num = idx + 1;
+        let Some((_, comment)) = line.split_once("// ") else {
+            continue;
+        };
+
+        if let Some(output) = comment.strip_prefix("expect: ") {
+            expected.output.push(output.to_owned());
+        } else if let Some(msg) = comment.strip_prefix("expect runtime error: ") {
+            expected.runtime_error = Some((line_num, msg.to_owned()));
+        } else if comment.starts_with("[line ") {
+            expected.compile_errors.push(comment.to_owned());
+        } else if comment.starts_with("Error") {
+            expected
+                .compile_errors
+                .push(format!("[line {line_num}] {comment}"));
+        }
+    }
+
+    expected
+}
+
+fn run(source: &str, stress: bool) -> Outcome {
+    let mut interpreter = Interpreter::new();
+    interpreter.set_stress_gc(stress);
+    let output = OutputBuffer::new();
+    interpreter.set_output(output.clone());
+
+    let mut outcome = Outcome::default();
+    match interpreter.eval(source) {
+        Ok(()) => {}
+        Err(Error::Runtime(err)) => outcome.runtime_error = Some((err.line, err.message)),
+        Err(Error::Compile(errors)) => {
+            outcome.compile_errors = errors.iter().map(ToString::to_string).collect();
+        }
+        Err(err) => panic!("unexpected error from eval: {err}"),
+    }
+
+    outcome.output = output.contents().lines().map(str::to_owned).collect();
+    outcome
+}
+
+fn report_diff(out: &mut String, expected: &Outcome, actual: &Outcome) {
+    diff_lines(out, "output", &expected.output, &actual.output);
+    diff_lines(
+        out,
+        "compile errors",
+        &expected.compile_errors,
+        &actual.compile_errors,
+    );
+
+    if expected.runtime_error != actual.runtime_error {
+        let describe = |err: &Option<(usize, String)>| match err {
+            Some((line, msg)) => format!("[line {line}] {msg}"),
+            None => "none".to_owned(),
+        };
+        _ = writeln!(out, "  runtime error:");
+        _ = writeln!(out, "    - {}", describe(&expected.runtime_error));
+        _ = writeln!(out, "    + {}", describe(&actual.runtime_error));
+    }
+    _ = writeln!(out);
+}
+
+//- is what the script expected, + is what actually happened
+fn diff_lines(out: &mut String, what: &str, expected: &[String], actual: &[String]) {
+    if expected == actual {
+        return;
+    }
+
+    _ = writeln!(out, "  {what}:");
+    for idx in 0..expected.len().max(actual.len()) {
+        match (expected.get(idx), actual.get(idx)) {
+            (Some(e), Some(a)) if e == a => _ = writeln!(out, "      {e}"),
+            (e, a) => {
+                if let Some(e) = e {
+                    _ = writeln!(out, "    - {e}");
+                }
+                if let Some(a) = a {
+                    _ = writeln!(out, "    + {a}");
+                }
+            }
+        }
+    }
+}
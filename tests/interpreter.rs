@@ -0,0 +1,249 @@
+use kara::{Error, Interpreter, OutputBuffer, OwnedValue};
+use std::io::Cursor;
+
+//The embedding api, with the collector stressed so anything the host is
+//holding onto that the vm can't see gets freed straight away
+fn interpreter() -> (Interpreter, OutputBuffer) {
+    let mut interpreter = Interpreter::new();
+    interpreter.set_stress_gc(true);
+    let output = OutputBuffer::new();
+    interpreter.set_output(output.clone());
+    (interpreter, output)
+}
+
+#[test]
+fn globals_round_trip() {
+    let (mut interpreter, output) = interpreter();
+    interpreter.set_global("name", "world").unwrap();
+    interpreter.set_global("count", 3).unwrap();
+    interpreter.set_global("ratio", 0.5).unwrap();
+    interpreter.set_global("missing", None::<bool>).unwrap();
+
+    interpreter
+        .eval(r#"var greeting = "hello " + name; print greeting; var total = count * ratio;"#)
+        .unwrap();
+
+    assert_eq!(output.contents(), "hello world\n");
+    assert_eq!(
+        interpreter.get_global("greeting").unwrap(),
+        OwnedValue::Str("hello world".to_owned())
+    );
+    assert_eq!(interpreter.get_global("count").unwrap(), OwnedValue::Int(3));
+    assert_eq!(interpreter.get_global("total").unwrap(), OwnedValue::Number(1.5));
+    assert_eq!(interpreter.get_global("missing").unwrap(), OwnedValue::Nil);
+    assert!(matches!(
+        interpreter.get_global("nope"),
+        Err(Error::UndefinedGlobal(name)) if name == "nope"
+    ));
+}
+
+#[test]
+fn conversions() {
+    assert_eq!(String::try_from(OwnedValue::from("s")).unwrap(), "s");
+    assert_eq!(f64::try_from(OwnedValue::from(2)).unwrap(), 2.0);
+    assert_eq!(i64::try_from(OwnedValue::from(7i64)).unwrap(), 7);
+    assert!(bool::try_from(OwnedValue::from(true)).unwrap());
+    assert!(<()>::try_from(OwnedValue::from(())).is_ok());
+    assert_eq!(OwnedValue::from(Some("x")), OwnedValue::Str("x".to_owned()));
+
+    //floats aren't rounded into ints
+    assert!(matches!(
+        i64::try_from(OwnedValue::Number(1.5)),
+        Err(Error::Conversion { expected: "int", found: "number" })
+    ));
+    assert!(matches!(
+        bool::try_from(OwnedValue::Nil),
+        Err(Error::Conversion { expected: "bool", found: "nil" })
+    ));
+}
+
+#[test]
+fn call_function_roots_its_arguments() {
+    let (mut interpreter, _) = interpreter();
+    interpreter
+        .eval("fun join(a, b, c) { return a + \"-\" + b + \"-\" + c; }")
+        .unwrap();
+
+    //each argument is a fresh string, interning the later ones
+    //collects, which would free the earlier ones if they weren't rooted
+    let args: Vec<OwnedValue> = ["first", "second", "third"]
+        .into_iter()
+        .map(|arg| format!("{arg} argument").into())
+        .collect();
+    let result = interpreter.call_function("join", &args).unwrap();
+
+    assert_eq!(
+        result,
+        OwnedValue::Str("first argument-second argument-third argument".to_owned())
+    );
+}
+
+#[test]
+fn call_function_on_classes_and_natives() {
+    let (mut interpreter, _) = interpreter();
+    interpreter
+        .eval(
+            "class Empty {}
+             class Point { init(x, y) { this.x = x; this.y = y; } }",
+        )
+        .unwrap();
+
+    //no init, so the call is over before the vm ever runs
+    let empty = interpreter.call_function("Empty", &[]).unwrap();
+    assert_eq!(empty, OwnedValue::Object("Empty instance".to_owned()));
+
+    let point = interpreter.call_function("Point", &[1.into(), 2.into()]).unwrap();
+    assert_eq!(point, OwnedValue::Object("Point instance".to_owned()));
+
+    let len = interpreter.call_function("len", &["héllo".into()]).unwrap();
+    assert_eq!(len, OwnedValue::Int(5));
+
+    assert!(matches!(
+        interpreter.call_function("Empty", &[1.into()]),
+        Err(Error::Runtime(err)) if err.message == "Expected 0 arguments but got 1"
+    ));
+}
+
+#[test]
+fn call_function_argument_errors() {
+    let (mut interpreter, _) = interpreter();
+    interpreter.eval("fun id(x) { return x; }").unwrap();
+
+    assert!(matches!(
+        interpreter.call_function("nope", &[]),
+        Err(Error::UndefinedGlobal(_))
+    ));
+    assert!(matches!(
+        interpreter.call_function("id", &vec![OwnedValue::Nil; 256]),
+        Err(Error::TooManyArguments)
+    ));
+    //objects can't be passed back in
+    assert!(matches!(
+        interpreter.call_function("id", &[1.into(), OwnedValue::Object("thing".to_owned())]),
+        Err(Error::Conversion { found: "object", .. })
+    ));
+
+    //none of that leaves anything behind
+    let result = interpreter.call_function("id", &["still fine".into()]).unwrap();
+    assert_eq!(result, OwnedValue::Str("still fine".to_owned()));
+}
+
+#[test]
+fn recovers_after_runtime_errors() {
+    let (mut interpreter, output) = interpreter();
+    interpreter
+        .eval(
+            "var calls = 0;
+             fun fail(x) { calls = calls + 1; return x - nil; }
+             fun ok(x) { calls = calls + 1; return x * 2; }",
+        )
+        .unwrap();
+
+    let Err(Error::Runtime(err)) = interpreter.call_function("fail", &[1.into()]) else {
+        panic!("expected a runtime error");
+    };
+    assert_eq!(err.message, "Operands must be numbers");
+    assert_eq!(err.trace.len(), 1);
+    assert_eq!(err.trace[0].function.as_deref(), Some("fail"));
+
+    assert!(matches!(interpreter.eval("print undefined;"), Err(Error::Runtime(_))));
+    assert!(matches!(interpreter.eval("print ;"), Err(Error::Compile(_))));
+
+    //globals from before the errors are still there and calls still work
+    assert_eq!(interpreter.call_function("ok", &[21.into()]).unwrap(), OwnedValue::Int(42));
+    interpreter.eval("print calls;").unwrap();
+    assert_eq!(output.contents(), "2\n");
+}
+
+#[test]
+fn natives_defined_by_the_host() {
+    fn shout(args: &[OwnedValue]) -> Result<OwnedValue, String> {
+        match &args[0] {
+            OwnedValue::Str(chars) => Ok(format!("{}!", chars.to_uppercase()).into()),
+            _ => Err("shout() expects a string".to_owned()),
+        }
+    }
+
+    let (mut interpreter, output) = interpreter();
+    interpreter.define_native("shout", 1, shout);
+    interpreter.eval(r#"print shout("hey");"#).unwrap();
+    assert_eq!(output.contents(), "HEY!\n");
+
+    assert!(matches!(
+        interpreter.eval("shout(1);"),
+        Err(Error::Runtime(err)) if err.message == "shout() expects a string"
+    ));
+    assert!(matches!(
+        interpreter.eval("shout();"),
+        Err(Error::Runtime(err)) if err.message == "Expected 1 arguments but got 0"
+    ));
+}
+
+#[test]
+fn host_natives_only_see_owned_values() {
+    fn describe(args: &[OwnedValue]) -> Result<OwnedValue, String> {
+        let parts: Vec<String> = args.iter().map(|arg| format!("{arg:?}")).collect();
+        Ok(parts.join(", ").into())
+    }
+
+    fn leak(_args: &[OwnedValue]) -> Result<OwnedValue, String> {
+        Ok(OwnedValue::Object("thing".to_owned()))
+    }
+
+    let (mut interpreter, output) = interpreter();
+    interpreter.define_native("describe", 3, describe);
+    interpreter.define_native("leak", 0, leak);
+
+    //the result is interned after the call, which collects under stress,
+    //so anything from the script has to have stayed rooted through it
+    interpreter
+        .eval(
+            r#"class Box {}
+               var label = "a" + "b";
+               for (var i = 0; i < 3; i = i + 1) print describe(label, Box(), i);"#,
+        )
+        .unwrap();
+    assert_eq!(
+        output.contents(),
+        "Str(\"ab\"), Object(\"Box instance\"), Int(0)\n\
+         Str(\"ab\"), Object(\"Box instance\"), Int(1)\n\
+         Str(\"ab\"), Object(\"Box instance\"), Int(2)\n"
+    );
+
+    //objects can't be handed back in
+    assert!(matches!(
+        interpreter.eval("leak();"),
+        Err(Error::Runtime(err)) if err.message == "Expected nil, bool, number or string but found object"
+    ));
+}
+
+#[test]
+fn check_disassemble_and_echo() {
+    let (mut interpreter, output) = interpreter();
+
+    //neither of these runs anything
+    interpreter.check("print 1;").unwrap();
+    let disasm = interpreter.disassemble("print 1;").unwrap();
+    assert!(disasm.contains("OpPrint"), "{disasm}");
+    assert!(matches!(interpreter.check("print ;"), Err(Error::Compile(_))));
+    assert!(matches!(interpreter.disassemble("print ;"), Err(Error::Compile(_))));
+    assert_eq!(output.contents(), "");
+
+    interpreter.set_echo_expressions(true);
+    interpreter.eval("1 + 2; var x = 4;").unwrap();
+    interpreter.set_echo_expressions(false);
+    interpreter.eval("x;").unwrap();
+    assert_eq!(output.contents(), "3\n");
+}
+
+#[test]
+fn input_reads_lines_from_the_host() {
+    let (mut interpreter, _) = interpreter();
+    interpreter.set_input(Cursor::new("a\r\nb\n"));
+
+    //line endings are stripped, either kind, and running out gives nil
+    interpreter.eval("var first = input(); var second = input(); var third = input();").unwrap();
+    assert_eq!(interpreter.get_global("first").unwrap(), OwnedValue::Str("a".to_owned()));
+    assert_eq!(interpreter.get_global("second").unwrap(), OwnedValue::Str("b".to_owned()));
+    assert_eq!(interpreter.get_global("third").unwrap(), OwnedValue::Nil);
+}